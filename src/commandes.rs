@@ -1,16 +1,22 @@
+use std::cmp::max;
 use std::error::Error;
 use log::debug;
 use millegrilles_common_rust::bson::doc;
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chiffrage_cle::CommandeSauvegarderCle;
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::common_messages::verifier_reponse_ok;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
-use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::middleware::{ChiffrageFactoryTrait, sauvegarder_traiter_transaction};
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
-use millegrilles_common_rust::serde_json::json;
+use millegrilles_common_rust::serde_json::{json, Value};
 use millegrilles_common_rust::verificateur::VerificateurMessage;
 
+use crate::registre::{autoriser_action, Handler, HandlerCommande, TypeAction};
 use crate::common::*;
 use crate::constantes::*;
 use crate::gestionnaire::GestionnaireLanding;
@@ -21,88 +27,892 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValideAction, gesti
 {
     debug!("consommer_commande : {:?}", &m.message);
 
-    let user_id = m.get_user_id();
-    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    let handler = match autoriser_action(&m, TypeAction::Commande)?.handler {
+        Handler::Commande(h) => h,
+        _ => Err(format!("commandes.consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, m.action))?,
+    };
 
-    if role_prive && user_id.is_some() {
-        // Ok, commande usager
-    } else {
-        match m.verifier_exchanges(vec!(Securite::L1Public, Securite::L2Prive, Securite::L3Protege, Securite::L4Secure)) {
-            true => Ok(()),
-            false => {
-                // Verifier si on a un certificat delegation globale
-                match m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
-                    true => Ok(()),
-                    false => Err(format!("grosfichiers.consommer_commande: Commande autorisation invalide pour message {:?}", m.correlation_id)),
-                }
-            }
-        }?;
+    match handler {
+        HandlerCommande::CreerNouvelleApplication => commande_creer_nouvelle_application(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderApplication => commande_sauvegarder_application(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderPageApplication => commande_sauvegarder_page_application(middleware, m, gestionnaire).await,
+        HandlerCommande::PublierApplication => commande_publier_application(middleware, m, gestionnaire).await,
+        HandlerCommande::RestaurerVersionApplication => commande_restaurer_version_application(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderHoraireApplication => commande_sauvegarder_horaire_application(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderLimiteSoumissions => commande_sauvegarder_limite_soumissions(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderLimiteDebit => commande_sauvegarder_limite_debit(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderDifficulteDefi => commande_sauvegarder_difficulte_defi(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderConfigurationSpam => commande_sauvegarder_configuration_spam(middleware, m, gestionnaire).await,
+        HandlerCommande::LibererMessages => commande_liberer_messages(middleware, m, gestionnaire).await,
+        HandlerCommande::SupprimerMessages => commande_supprimer_messages(middleware, m, gestionnaire).await,
+        HandlerCommande::ChangerEtatMessages => commande_changer_etat_messages(middleware, m, gestionnaire).await,
+        HandlerCommande::EtiqueterMessages => commande_etiqueter_messages(middleware, m, gestionnaire).await,
+        HandlerCommande::AssignerMessages => commande_assigner_messages(middleware, m, gestionnaire).await,
+        HandlerCommande::BloquerSource => commande_bloquer_source(middleware, m, gestionnaire).await,
+        HandlerCommande::DebloquerSource => commande_debloquer_source(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderLimitesFichiers => commande_sauvegarder_limites_fichiers(middleware, m, gestionnaire).await,
+        HandlerCommande::SauvegarderTransfertMessagerie => commande_sauvegarder_transfert_messagerie(middleware, m, gestionnaire).await,
+        HandlerCommande::SupprimerApplication => commande_supprimer_application(middleware, m, gestionnaire).await,
+        HandlerCommande::ArchiverApplication => commande_archiver_application(middleware, m, gestionnaire).await,
+        HandlerCommande::RestaurerApplication => commande_restaurer_application(middleware, m, gestionnaire).await,
+        HandlerCommande::SoumettreMessage => commande_soumettre_message(middleware, m, gestionnaire).await,
     }
+}
+
+async fn commande_creer_nouvelle_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_creer_nouvelle_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionCreerNouvelleApplication = m.message.get_msg().map_contenu(None)?;
+
+    verifier_autorisation_usager(&m)?;
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
 
-    match m.action.as_str() {
-        // Commandes
+async fn commande_sauvegarder_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderApplication = m.message.get_msg().map_contenu(None)?;
 
-        // Transactions
-        TRANSACTION_CREER_NOUVELLE_APPLICATION => commande_creer_nouvelle_application(middleware, m, gestionnaire).await,
-        TRANSACTION_SAUVEGARDER_APPLICATION => commande_sauvegarder_application(middleware, m, gestionnaire).await,
+    let user_id = verifier_autorisation_usager(&m)?;
 
-        // Commandes inconnues
-        _ => Err(format!("core_backup.consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, m.action))?,
+    if let Some(app) = charger_application_usager(middleware, &commande.application_id, &user_id).await? {
+        if app.supprime == Some(true) {
+            let reponse = json!({"ok": false, "err": "Application supprimee"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
     }
+
+    if let Some(champs) = commande.champs.as_ref() {
+        if let Err(e) = valider_champs_formulaire(champs) {
+            let reponse = json!({"ok": false, "err": e});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
-async fn commande_creer_nouvelle_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+async fn commande_sauvegarder_page_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + ValidateurX509
 {
-    debug!("commande_creer_nouvelle_application Consommer commande : {:?}", & m.message);
-    let commande: TransactionCreerNouvelleApplication = m.message.get_msg().map_contenu(None)?;
+    debug!("commande_sauvegarder_page_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderPageApplication = m.message.get_msg().map_contenu(None)?;
 
-    let user_id = match m.get_user_id() {
-        Some(inner) => inner,
-        None => Err(format!("commande_creer_nouvelle_application User_id absent du certificat"))?
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = valider_page_application(&commande.page) {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_publier_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_publier_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionPublierApplication = m.message.get_msg().map_contenu(None)?;
+
+    let app = match charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        Ok(inner) => inner,
+        Err(reponse) => return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
     };
+    if app.brouillon.is_none() {
+        let reponse = json!({"ok": false, "err": "Aucun brouillon a publier"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
-    if role_prive {
-        // Ok
-    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_creer_nouvelle_application: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_restaurer_version_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_restaurer_version_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionRestaurerVersionApplication = m.message.get_msg().map_contenu(None)?;
+
+    let app = match charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        Ok(inner) => inner,
+        Err(reponse) => return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    };
+
+    let filtre = doc! {
+        CHAMP_APPLICATION_ID: &commande.application_id,
+        CHAMP_USER_ID: &app.user_id,
+        CHAMP_VERSION: commande.version,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_VERSIONS)?;
+    if collection.count_documents(filtre, None).await? == 0 {
+        let reponse = json!({"ok": false, "err": "Version inconnue"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
     }
 
     // Traiter la transaction
     Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
-async fn commande_sauvegarder_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+async fn commande_sauvegarder_horaire_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + ValidateurX509
 {
-    debug!("commande_sauvegarder_application Consommer commande : {:?}", & m.message);
-    let commande: TransactionSauvegarderApplication = m.message.get_msg().map_contenu(None)?;
+    debug!("commande_sauvegarder_horaire_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderHoraireApplication = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.valider() {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_limite_soumissions<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_limite_soumissions Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderLimiteSoumissions = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.valider() {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_limite_debit<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_limite_debit Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderLimiteDebit = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
 
+    if let Err(e) = commande.valider() {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_difficulte_defi<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_difficulte_defi Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderDifficulteDefi = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.valider() {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_configuration_spam<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_configuration_spam Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderConfigurationSpam = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.configuration.as_ref().map(|c| c.valider()).unwrap_or(Ok(())) {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_limites_fichiers<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_limites_fichiers Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderLimitesFichiers = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.limites.as_ref().map(|c| c.valider()).unwrap_or(Ok(())) {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_sauvegarder_transfert_messagerie<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_transfert_messagerie Consommer commande : {:?}", & m.message);
+    let commande: TransactionSauvegarderTransfertMessagerie = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_supprimer_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_supprimer_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionSupprimerApplication = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_archiver_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_archiver_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionArchiverApplication = m.message.get_msg().map_contenu(None)?;
+
+    if let Err(reponse) = charger_application_autorisee(middleware, &m, &commande.application_id).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_restaurer_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_restaurer_application Consommer commande : {:?}", & m.message);
+    let commande: TransactionRestaurerApplication = m.message.get_msg().map_contenu(None)?;
+
+    // Application dans la corbeille, ne pas utiliser charger_application_autorisee (refuse supprime)
+    let user_id = verifier_autorisation_usager(&m)?;
+    match charger_application_usager(middleware, &commande.application_id, &user_id).await? {
+        Some(app) => if app.supprime != Some(true) {
+            let reponse = json!({"ok": false, "err": "Application n'est pas dans la corbeille"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        },
+        None => {
+            // Application inconnue ou deja purgee
+            let reponse = json!({"ok": false, "err": "Application inconnue"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_liberer_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_liberer_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionLibererMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = verifier_autorisation_usager(&m)?;
+
+    if let Some(reponse) = verifier_messages_usager(middleware, &commande.message_ids, &user_id, true).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_supprimer_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_supprimer_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionSupprimerMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = verifier_autorisation_usager(&m)?;
+
+    if let Some(reponse) = verifier_messages_usager(middleware, &commande.message_ids, &user_id, false).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_changer_etat_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_changer_etat_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionChangerEtatMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = verifier_autorisation_usager(&m)?;
+
+    if let Some(reponse) = verifier_messages_usager(middleware, &commande.message_ids, &user_id, false).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Tous les messages doivent permettre la transition
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &commande.message_ids},
+        CHAMP_USER_ID: &user_id,
+        CHAMP_ETAT: filtre_etats_messages(commande.etat.etats_precedents()),
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let nombre = collection.count_documents(filtre, None).await?;
+    if nombre as usize != commande.message_ids.len() {
+        let reponse = json!({"ok": false, "err": format!("Transition vers {} invalide", commande.etat.as_str())});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_etiqueter_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_etiqueter_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionEtiqueterMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = verifier_autorisation_usager(&m)?;
+
+    if let Some(reponse) = verifier_messages_usager(middleware, &commande.message_ids, &user_id, false).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    let mut nombre_etiquettes = 0;
+    for etiquettes in [commande.ajouter.as_ref(), commande.retirer.as_ref()].into_iter().flatten() {
+        if let Err(e) = normaliser_etiquettes(etiquettes) {
+            let reponse = json!({"ok": false, "err": e});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+        nombre_etiquettes += etiquettes.len();
+    }
+    if nombre_etiquettes == 0 {
+        let reponse = json!({"ok": false, "err": "Aucune etiquette"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_assigner_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_assigner_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionAssignerMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = verifier_autorisation_usager(&m)?;
+
+    if let Some(reponse) = verifier_messages_usager(middleware, &commande.message_ids, &user_id, false).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Some(assigne) = commande.assigne.as_ref() {
+        if assigne.trim().is_empty() || assigne.len() > TAILLE_MAXIMUM_ASSIGNE {
+            let reponse = json!({"ok": false, "err": "Assigne invalide"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_bloquer_source<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_bloquer_source Consommer commande : {:?}", & m.message);
+    let commande: TransactionBloquerSource = m.message.get_msg().map_contenu(None)?;
+
+    if let Some(reponse) = verifier_autorisation_blocage(middleware, &m, commande.application_id.as_ref()).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Err(e) = commande.valider() {
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_debloquer_source<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_debloquer_source Consommer commande : {:?}", & m.message);
+    let commande: TransactionDebloquerSource = m.message.get_msg().map_contenu(None)?;
+
+    if let Some(reponse) = verifier_autorisation_blocage(middleware, &m, commande.application_id.as_ref()).await? {
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+/// Un blocage d'application est gere par son proprietaire. La liste globale (sans application_id)
+/// requiert la delegation globale proprietaire.
+async fn verifier_autorisation_blocage<M>(middleware: &M, m: &MessageValideAction, application_id: Option<&String>)
+    -> Result<Option<Value>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let application_id = match application_id {
+        Some(inner) => inner,
+        None => {
+            if ! m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+                Err(format!("commandes.verifier_autorisation_blocage: Delegation globale requise pour message {:?}", m.correlation_id))?
+            }
+            return Ok(None)
+        }
+    };
+
+    match charger_application_autorisee(middleware, m, application_id).await? {
+        Ok(_) => Ok(None),
+        Err(reponse) => Ok(Some(reponse))
+    }
+}
+
+/// Verifie que tous les messages existent et appartiennent a l'usager. Retourne une reponse
+/// d'erreur au besoin.
+async fn verifier_messages_usager<M>(middleware: &M, message_ids: &Vec<String>, user_id: &str, quarantaine: bool)
+    -> Result<Option<Value>, Box<dyn Error>>
+    where M: MongoDao
+{
+    if message_ids.is_empty() || message_ids.len() > NOMBRE_MAXIMUM_MESSAGES_TRANSACTION {
+        return Ok(Some(json!({"ok": false, "err": format!("Nombre de messages invalide (1 a {})", NOMBRE_MAXIMUM_MESSAGES_TRANSACTION)})))
+    }
+
+    let mut filtre = doc! { CHAMP_MESSAGE_ID: {"$in": message_ids}, CHAMP_USER_ID: user_id };
+    if quarantaine {
+        filtre.insert(CHAMP_QUARANTAINE, true);
+    }
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let nombre = collection.count_documents(filtre, None).await?;
+    if nombre as usize != message_ids.len() {
+        return Ok(Some(json!({"ok": false, "err": "Messages inconnus"})))
+    }
+
+    Ok(None)
+}
+
+/// Autorisation d'une action usager : compte prive ou delegation globale proprietaire.
+/// Retourne le user_id du certificat.
+fn verifier_autorisation_usager(m: &MessageValideAction) -> Result<String, String> {
     let user_id = match m.get_user_id() {
         Some(inner) => inner,
-        None => Err(format!("commande_creer_nouvelle_application User_id absent du certificat"))?
+        None => Err(format!("commandes.verifier_autorisation_usager User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
     let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
     if role_prive {
         // Ok
     } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
         // Ok
     } else {
-        Err(format!("commandes.commande_creer_nouvelle_application: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+        Err(format!("commandes.verifier_autorisation_usager: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    Ok(user_id)
+}
+
+/// Verifie l'autorisation de l'usager et charge son application. Le resultat interne contient la
+/// reponse d'erreur lorsque l'application est inconnue ou dans la corbeille.
+async fn charger_application_autorisee<M>(middleware: &M, m: &MessageValideAction, application_id: &str)
+    -> Result<Result<DocApplication, Value>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let user_id = verifier_autorisation_usager(m)?;
+    match charger_application_usager(middleware, application_id, &user_id).await? {
+        Some(inner) => match inner.supprime {
+            Some(true) => Ok(Err(json!({"ok": false, "err": "Application supprimee"}))),
+            _ => Ok(Ok(inner))
+        },
+        None => Ok(Err(json!({"ok": false, "err": "Application inconnue"})))
+    }
+}
+
+/// Charge une application qui appartient a l'usager.
+async fn charger_application_usager<M>(middleware: &M, application_id: &str, user_id: &str)
+    -> Result<Option<DocApplication>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_APPLICATION_ID: application_id, CHAMP_USER_ID: user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    match collection.find_one(filtre, None).await? {
+        Some(d) => Ok(Some(convertir_bson_deserializable(d)?)),
+        None => Ok(None)
+    }
+}
+
+async fn commande_soumettre_message<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_soumettre_message Consommer commande : {:?}", & m.message);
+    let commande: TransactionSoumettreMessage = m.message.get_msg().map_contenu(None)?;
+
+    // Commande publique : s'assurer que l'application existe et est active
+    let filtre = doc! { CHAMP_APPLICATION_ID: &commande.application_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application: DocApplication = match collection.find_one(filtre, None).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => {
+            let reponse = json!({"ok": false, "err": "Application inconnue"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    };
+
+    // Sources bloquees : la soumission est abandonnee avant toute sauvegarde
+    let source = get_source_soumission(&m, commande.adresse_source.as_ref());
+    if est_source_bloquee(middleware, &application.application_id, &source, commande.jeton_client.as_ref()).await? {
+        debug!("commande_soumettre_message Source bloquee {:?} pour application {}", source, application.application_id);
+        let reponse = json!({"ok": false, "err": "Soumission refusee", "code": "bloque"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Limites de debit : globale, application et source
+    if ! verifier_debit(gestionnaire, &application, source.hachage.as_str()).await {
+        incrementer_rejet(middleware, &application.application_id, REJET_DEBIT).await?;
+        let reponse = json!({"ok": false, "err": "Trop de soumissions, reessayer plus tard", "code": "debit"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if application.actif != Some(true) {
+        let reponse = json!({"ok": false, "err": "Application inactive"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Verifier la fenetre d'ouverture immediatement, sans attendre la cedule
+    if application.est_ouvert_horaire(&Utc::now()) == Some(false) {
+        let reponse = json!({"ok": false, "err": "Application fermee", "code": "ferme"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Refus rapide. La place est reservee atomiquement par la transaction (compteurs).
+    if charger_places_restantes(middleware, &application, &Utc::now()).await? == Some(0) {
+        let reponse = json!({"ok": false, "err": "Limite de soumissions atteinte", "code": "complet"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    if let Some(jeton) = commande.jeton_formulaire.as_ref() {
+        if ! verifier_jeton_formulaire(middleware, &application.application_id, jeton).await? {
+            incrementer_rejet(middleware, &application.application_id, REJET_JETON).await?;
+            let reponse = json!({"ok": false, "err": "Jeton de formulaire invalide ou expire", "code": "jeton"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    if let Some(difficulte) = application.difficulte_defi {
+        if let Err(e) = verifier_defi(middleware, &application.application_id, difficulte, commande.defi.as_ref()).await? {
+            debug!("commande_soumettre_message Defi refuse pour application {} : {}", application.application_id, e);
+            incrementer_rejet(middleware, &application.application_id, REJET_DEFI).await?;
+            let reponse = json!({"ok": false, "err": e, "code": "defi"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    if commande.data_chiffre.len() > TAILLE_MAXIMUM_MESSAGE {
+        let reponse = json!({"ok": false, "err": "Message trop volumineux"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Valider l'enveloppe (non chiffree) avec le formulaire de l'application
+    if let Some(champs) = application.champs.as_ref() {
+        let resultat = match commande.enveloppe.as_ref() {
+            Some(enveloppe) => valider_enveloppe_message(champs, enveloppe),
+            None => Err(vec![ErreurChamp { code_interne: "enveloppe".into(), erreur: "manquante".into() }])
+        };
+        if let Err(erreurs) = resultat {
+            let reponse = json!({"ok": false, "err": "Message invalide", "champs": erreurs});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    // Valider les fichiers joints avec les limites de l'application. Toujours verifier, le
+    // nombre_fichiers declare doit correspondre aux fichiers recus (aucun si la liste est absente).
+    let fichiers = commande.fichiers.as_ref().map(|f| f.as_slice()).unwrap_or(&[]);
+    let limites = application.limites_fichiers.clone().unwrap_or_default();
+    if let Err(erreurs) = valider_fichiers_message(&limites, application.champs.as_ref(), commande.enveloppe.as_ref(), fichiers) {
+        let reponse = json!({"ok": false, "err": "Fichiers invalides", "champs": erreurs});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    // Sauvegarder la cle du message avant de conserver la transaction
+    let commande_maitredescles = match commande.commande_maitredescles.as_ref() {
+        Some(inner) => inner,
+        None => {
+            let reponse = json!({"ok": false, "err": "Commande maitre des cles manquante"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    };
+    if let Err(e) = verifier_commande_maitredescles(&commande, commande_maitredescles, DOMAINE_NOM) {
+        debug!("commande_soumettre_message Commande maitre des cles invalide : {}", e);
+        let reponse = json!({"ok": false, "err": e});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+    // Cle pour Messagerie, sauvegardee seulement lors du transfert
+    if let Some(commande_messagerie) = commande.commande_maitredescles_messagerie.as_ref() {
+        if let Err(e) = verifier_commande_maitredescles(&commande, commande_messagerie, DOMAINE_MESSAGERIE_NOM) {
+            debug!("commande_soumettre_message Commande maitre des cles (Messagerie) invalide : {}", e);
+            let reponse = json!({"ok": false, "err": e});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+        }
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
+        .exchanges(vec![Securite::L4Secure])
+        .build();
+    let cle_sauvegardee = match middleware.transmettre_commande(routage, commande_maitredescles, true).await? {
+        Some(reponse) => {
+            debug!("commande_soumettre_message Reponse sauvegarde cle : {:?}", reponse);
+            verifier_reponse_ok(&reponse)
+        },
+        None => false
+    };
+    if ! cle_sauvegardee {
+        let reponse = json!({"ok": false, "err": "Erreur sauvegarde cle"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
     }
 
     // Traiter la transaction
     Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
+/// Verifie si une des sources de la soumission (fingerprint, hachage de source ou jeton client) est
+/// bloquee pour l'application ou globalement.
+async fn est_source_bloquee<M>(middleware: &M, application_id: &str, source: &SourceSoumission, jeton_client: Option<&String>)
+    -> Result<bool, String>
+    where M: MongoDao
+{
+    let mut sources = vec![source.fingerprint.as_str(), source.hachage.as_str()];
+    if let Some(j) = jeton_client {
+        sources.push(j.as_str());
+    }
+    let filtre = doc! {
+        CHAMP_SOURCE: {"$in": sources},
+        "$or": [
+            {CHAMP_APPLICATION_ID: application_id},
+            {CHAMP_APPLICATION_ID: None::<&str>},
+        ],
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_BLOCAGES)?;
+    match collection.count_documents(filtre, None).await {
+        Ok(n) => Ok(n > 0),
+        Err(e) => Err(format!("commandes.est_source_bloquee Erreur verification blocage : {:?}", e))
+    }
+}
+
+/// Applique les limites de debit de la source, de l'application puis globale. Retourne false si une
+/// des limites est depassee. La source est le hachage de l'emetteur (SourceSoumission.hachage) : un
+/// relais partage est limite par visiteur et non pour l'ensemble de ses visiteurs.
+async fn verifier_debit(gestionnaire: &GestionnaireLanding, application: &DocApplication, source: &str) -> bool {
+    let limiteur = gestionnaire.limiteur_debit.as_ref();
+
+    // La source est verifiee en premier et ses tentatives refusees restent comptees. Les fenetres
+    // de l'application et globale ne comptent que les soumissions acceptees par les limites plus
+    // etroites : une source qui insiste ne peut pas bloquer les autres.
+    let limite_source = match application.limite_debit_source.as_ref() {
+        Some(inner) => inner,
+        None => &limiteur.limite_source_defaut
+    };
+    let cle = format!("source:{}:{}", application.application_id, source);
+    if ! limiteur.verifier(cle.as_str(), limite_source).await {
+        debug!("verifier_debit Limite source {} atteinte pour application {}", source, application.application_id);
+        return false
+    }
+
+    let reservation_application = match application.limite_debit.as_ref() {
+        Some(limite) => {
+            let cle = format!("application:{}", application.application_id);
+            match limiteur.reserver(cle.as_str(), limite).await {
+                Some(inner) => Some((cle, inner)),
+                None => {
+                    debug!("verifier_debit Limite application {} atteinte", application.application_id);
+                    return false
+                }
+            }
+        },
+        None => None
+    };
+
+    if limiteur.reserver("global", &limiteur.limite_globale).await.is_none() {
+        debug!("verifier_debit Limite globale atteinte");
+        if let Some((cle, reservation)) = reservation_application {
+            limiteur.annuler(cle.as_str(), reservation.as_str()).await;
+        }
+        return false
+    }
+
+    true
+}
+
+/// Verifie la solution du defi et le marque comme utilise (un defi ne peut pas etre rejoue).
+/// Le resultat interne contient la raison du refus.
+async fn verifier_defi<M>(middleware: &M, application_id: &str, difficulte: u32, solution: Option<&SolutionDefi>)
+    -> Result<Result<(), String>, String>
+    where M: MongoDao
+{
+    let solution = match solution {
+        Some(inner) => inner,
+        None => return Ok(Err(String::from("Defi requis")))
+    };
+
+    let filtre = doc! { CHAMP_DEFI_ID: &solution.defi_id, CHAMP_APPLICATION_ID: application_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_DEFIS)?;
+    let defi: DocDefiSoumission = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("commandes.verifier_defi Erreur mapping defi : {:?}", e))?
+        },
+        Ok(None) => return Ok(Err(String::from("Defi inconnu"))),
+        Err(e) => Err(format!("commandes.verifier_defi Erreur chargement defi : {:?}", e))?
+    };
+
+    if defi.expiration < Utc::now().timestamp() {
+        return Ok(Err(String::from("Defi expire")))
+    }
+    // La difficulte courante de l'application s'applique si elle a ete augmentee depuis l'emission
+    let difficulte = max(difficulte, defi.difficulte);
+    if ! verifier_solution_defi(&defi.defi_id, &solution.solution, difficulte) {
+        return Ok(Err(String::from("Solution du defi invalide")))
+    }
+
+    // Marquer le defi utilise de maniere atomique, une seule soumission peut le consommer
+    let filtre = doc! { CHAMP_DEFI_ID: &defi.defi_id, CHAMP_UTILISE: false };
+    let ops = doc! { "$set": { CHAMP_UTILISE: true } };
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => if r.modified_count != 1 {
+            return Ok(Err(String::from("Defi deja utilise")))
+        },
+        Err(e) => Err(format!("commandes.verifier_defi Erreur maj defi : {:?}", e))?
+    }
+
+    Ok(Ok(()))
+}
+
+/// Verifie qu'un jeton de formulaire a ete emis pour l'application a la date indiquee et le marque
+/// comme utilise (un jeton ne peut pas etre rejoue). La date du jeton est conservee dans la
+/// transaction pour la detection de spam.
+async fn verifier_jeton_formulaire<M>(middleware: &M, application_id: &str, jeton: &JetonFormulaire)
+    -> Result<bool, String>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_JETON_ID: &jeton.jeton_id,
+        CHAMP_APPLICATION_ID: application_id,
+        "date_creation": jeton.date_creation,
+        CHAMP_EXPIRATION: {"$gte": Utc::now().timestamp()},
+        CHAMP_UTILISE: false,
+    };
+    // Marquer le jeton utilise de maniere atomique, une seule soumission peut le consommer
+    let ops = doc! { "$set": { CHAMP_UTILISE: true } };
+    let collection = middleware.get_collection(NOM_COLLECTION_JETONS)?;
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => Ok(r.modified_count == 1),
+        Err(e) => Err(format!("commandes.verifier_jeton_formulaire Erreur maj jeton : {:?}", e))
+    }
+}
+
+/// Compte une soumission rejetee dans les statistiques de l'application.
+async fn incrementer_rejet<M>(middleware: &M, application_id: &str, raison: &str) -> Result<(), String>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_APPLICATION_ID: application_id };
+    let champ_rejet = format!("{}.{}", CHAMP_REJETS, raison);
+    let ops = doc! {
+        "$inc": { champ_rejet: 1 },
+        "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_STATISTIQUES)?;
+    if let Err(e) = collection.update_one(filtre, ops, options).await {
+        Err(format!("commandes.incrementer_rejet Erreur maj statistiques {} : {:?}", application_id, e))?
+    }
+    Ok(())
+}
+
+/// Verifie que la commande de cle correspond au message soumis.
+fn verifier_commande_maitredescles(commande: &TransactionSoumettreMessage, commande_maitredescles: &CommandeSauvegarderCle, domaine: &str)
+    -> Result<(), String>
+{
+    if commande_maitredescles.hachage_bytes != commande.ref_hachage_bytes {
+        Err(format!("Cle (hachage_bytes) ne correspond pas au message"))?
+    }
+    if commande_maitredescles.domaine.as_str() != domaine {
+        Err(format!("Cle avec mauvais domaine : {}", commande_maitredescles.domaine))?
+    }
+    match commande_maitredescles.identificateurs_document.get(CHAMP_APPLICATION_ID) {
+        Some(application_id) => if application_id != &commande.application_id {
+            Err(format!("Cle pour une autre application : {}", application_id))?
+        },
+        None => Err(format!("Cle sans identificateur application_id"))?
+    }
+    if commande_maitredescles.cles.is_empty() {
+        Err(format!("Commande de cle sans cles chiffrees"))?
+    }
+    Ok(())
+}
+
 // async fn commande_sauvegader_groupe<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
 //     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
 //     where M: GenerateurMessages + MongoDao + ValidateurX509
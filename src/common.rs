@@ -1,19 +1,556 @@
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+
+use millegrilles_common_rust::bson::{Bson, doc};
 use millegrilles_common_rust::chiffrage::FormatChiffrage;
 use millegrilles_common_rust::chiffrage_cle::CommandeSauvegarderCle;
+use millegrilles_common_rust::chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::openssl::sha::sha256;
+use millegrilles_common_rust::recepteur_messages::MessageValideAction;
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::constantes::Securite;
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
 
+use crate::constantes::*;
+use crate::limiteur::LimiteDebit;
+use crate::spam::{ConfigurationSpam, ResultatSpam};
+
 /// Commande/Transaction de sauvegarde d'une categorie usager.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransactionCreerNouvelleApplication {
 }
 
-/// Document de categorie pour un usager (collection mongo)
+/// Document d'une application (collection mongo). Les champs nom, champs et page sont ceux
+/// de la version publiee. Les modifications sont conservees dans le brouillon.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DocApplication {
     pub user_id: String,
     pub application_id: String,
     pub nom: Option<String>,
     pub actif: Option<bool>,
+    pub champs: Option<Vec<ChampFormulaire>>,
+    pub supprime: Option<bool>,
+    pub date_suppression: Option<i64>,
+    pub archive: Option<bool>,
+    pub page: Option<PageApplication>,
+    pub brouillon: Option<ContenuApplication>,
+    pub version_publiee: Option<i64>,
+    pub date_ouverture: Option<i64>,
+    pub date_fermeture: Option<i64>,
+    pub horaire: Option<HoraireApplication>,
+    /// Dernier etat applique par l'horaire (cedule)
+    pub ouvert_horaire: Option<bool>,
+    pub limite_soumissions: Option<LimiteSoumissions>,
+    pub limite_debit: Option<LimiteDebit>,
+    pub limite_debit_source: Option<LimiteDebit>,
+    /// Difficulte de la preuve de travail exigee des soumissions (None : aucun defi)
+    pub difficulte_defi: Option<u32>,
+    pub configuration_spam: Option<ConfigurationSpam>,
+    pub limites_fichiers: Option<LimitesFichiers>,
+    /// Transferer les messages recus vers la boite de Messagerie du proprietaire
+    pub transfert_messagerie: Option<bool>,
+}
+
+/// Horaire recurrent d'ouverture d'une application.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoraireApplication {
+    /// Jours d'ouverture, 0 = lundi ... 6 = dimanche
+    pub jours: Vec<u32>,
+    /// Minutes depuis minuit (heure locale). Si fermeture < ouverture, l'horaire passe minuit.
+    pub minute_ouverture: u32,
+    pub minute_fermeture: u32,
+    /// Decalage de l'heure locale par rapport a UTC
+    pub decalage_utc_minutes: Option<i32>,
+}
+
+impl HoraireApplication {
+    pub fn valider(&self) -> Result<(), String> {
+        if self.jours.is_empty() || self.jours.iter().any(|j| *j > 6) {
+            Err(format!("horaire: jours invalides"))?
+        }
+        if self.minute_ouverture >= 24 * 60 || self.minute_fermeture >= 24 * 60 {
+            Err(format!("horaire: minutes invalides"))?
+        }
+        if self.minute_ouverture == self.minute_fermeture {
+            Err(format!("horaire: ouverture et fermeture identiques"))?
+        }
+        if let Some(d) = self.decalage_utc_minutes {
+            if d.abs() > 14 * 60 { Err(format!("horaire: decalage_utc_minutes invalide"))? }
+        }
+        Ok(())
+    }
+
+    pub fn est_ouvert(&self, date: &DateTime<Utc>) -> bool {
+        let date_locale = *date + Duration::minutes(self.decalage_utc_minutes.unwrap_or(0) as i64);
+        let jour = date_locale.weekday().num_days_from_monday();
+        let jour_precedent = (jour + 6) % 7;
+        let minute = date_locale.hour() * 60 + date_locale.minute();
+
+        if self.minute_ouverture < self.minute_fermeture {
+            self.jours.contains(&jour) && minute >= self.minute_ouverture && minute < self.minute_fermeture
+        } else {
+            // Horaire de nuit, commence le jour d'ouverture et se termine le lendemain
+            (self.jours.contains(&jour) && minute >= self.minute_ouverture) ||
+                (self.jours.contains(&jour_precedent) && minute < self.minute_fermeture)
+        }
+    }
+}
+
+impl DocApplication {
+    /// Etat d'ouverture selon les dates et l'horaire. None si l'application n'a aucun horaire.
+    pub fn est_ouvert_horaire(&self, date: &DateTime<Utc>) -> Option<bool> {
+        if self.date_ouverture.is_none() && self.date_fermeture.is_none() && self.horaire.is_none() {
+            return None
+        }
+        let ts = date.timestamp();
+        let ouvert = self.date_ouverture.map_or(true, |d| ts >= d) &&
+            self.date_fermeture.map_or(true, |d| ts < d) &&
+            self.horaire.as_ref().map_or(true, |h| h.est_ouvert(date));
+        Some(ouvert)
+    }
+}
+
+/// Transaction de sauvegarde de l'horaire d'une application (remplace l'horaire existant).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderHoraireApplication {
+    pub application_id: String,
+    pub date_ouverture: Option<i64>,
+    pub date_fermeture: Option<i64>,
+    pub horaire: Option<HoraireApplication>,
+}
+
+impl TransactionSauvegarderHoraireApplication {
+    pub fn valider(&self) -> Result<(), String> {
+        if let (Some(o), Some(f)) = (self.date_ouverture, self.date_fermeture) {
+            if f <= o { Err(format!("date_fermeture doit etre apres date_ouverture"))? }
+        }
+        if let Some(h) = self.horaire.as_ref() {
+            h.valider()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeriodeLimite {
+    Jour,
+    Semaine,
+    Mois,
+}
+
+/// Nombre maximal de soumissions acceptees, au total ou par periode (UTC).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LimiteSoumissions {
+    pub maximum: i64,
+    /// Aucune periode : limite totale pour la duree de vie de l'application
+    pub periode: Option<PeriodeLimite>,
+}
+
+impl LimiteSoumissions {
+    /// Cle du compteur (collection compteurs) de la periode qui contient la date.
+    pub fn cle_periode(&self, date: &DateTime<Utc>) -> String {
+        cle_periode(self.periode.as_ref(), date)
+    }
+}
+
+/// Cle de compteur pour une periode. Le compteur total utilise la cle "total".
+pub fn cle_periode(periode: Option<&PeriodeLimite>, date: &DateTime<Utc>) -> String {
+    match periode {
+        None => String::from("total"),
+        Some(PeriodeLimite::Jour) => format!("jour/{}", date.format("%Y-%m-%d")),
+        Some(PeriodeLimite::Semaine) => {
+            let semaine = date.iso_week();
+            format!("semaine/{}-W{:02}", semaine.year(), semaine.week())
+        },
+        Some(PeriodeLimite::Mois) => format!("mois/{}", date.format("%Y-%m")),
+    }
+}
+
+/// Compteur de soumissions d'une application pour une periode (collection compteurs).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocCompteurSoumissions {
+    pub application_id: String,
+    pub periode: String,
+    pub nombre: i64,
+}
+
+/// Nombre de soumissions restantes pour la periode courante. None si l'application n'a pas de limite.
+pub async fn charger_places_restantes<M>(middleware: &M, application: &DocApplication, date: &DateTime<Utc>)
+    -> Result<Option<i64>, String>
+    where M: MongoDao
+{
+    let limite = match application.limite_soumissions.as_ref() {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+
+    let filtre = doc! {
+        CHAMP_APPLICATION_ID: &application.application_id,
+        CHAMP_PERIODE: limite.cle_periode(date),
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_COMPTEURS)?;
+    let nombre = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable::<DocCompteurSoumissions>(d) {
+            Ok(inner) => inner.nombre,
+            Err(e) => Err(format!("common.charger_places_restantes Erreur mapping compteur : {:?}", e))?
+        },
+        Ok(None) => 0,
+        Err(e) => Err(format!("common.charger_places_restantes Erreur chargement compteur : {:?}", e))?
+    };
+
+    Ok(Some(max(limite.maximum - nombre, 0)))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderLimiteSoumissions {
+    pub application_id: String,
+    /// None pour retirer la limite
+    pub limite: Option<LimiteSoumissions>,
+}
+
+impl TransactionSauvegarderLimiteSoumissions {
+    pub fn valider(&self) -> Result<(), String> {
+        if let Some(limite) = self.limite.as_ref() {
+            if limite.maximum < 1 { Err(format!("limite: maximum doit etre plus grand que 0"))? }
+        }
+        Ok(())
+    }
+}
+
+/// Limites de debit d'une application. Une limite absente retire la limite de l'application
+/// (la limite globale et la limite par source par defaut s'appliquent toujours).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderLimiteDebit {
+    pub application_id: String,
+    /// Limite pour toutes les soumissions de l'application
+    pub application: Option<LimiteDebit>,
+    /// Limite par source, remplace la limite par defaut
+    pub source: Option<LimiteDebit>,
+}
+
+impl TransactionSauvegarderLimiteDebit {
+    pub fn valider(&self) -> Result<(), String> {
+        if let Some(l) = self.application.as_ref() { l.valider()?; }
+        if let Some(l) = self.source.as_ref() { l.valider()?; }
+        Ok(())
+    }
+}
+
+/// Statistiques d'une application (collection statistiques). Ces valeurs ne proviennent pas de
+/// transactions et sont conservees lors d'une regeneration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocStatistiquesApplication {
+    pub application_id: String,
+    /// Nombre de soumissions rejetees par raison (e.g. REJET_DEBIT)
+    pub rejets: Option<HashMap<String, i64>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderDifficulteDefi {
+    pub application_id: String,
+    /// None pour ne plus exiger de defi
+    pub difficulte: Option<u32>,
+}
+
+impl TransactionSauvegarderDifficulteDefi {
+    pub fn valider(&self) -> Result<(), String> {
+        if let Some(d) = self.difficulte {
+            if d < 1 || d > DIFFICULTE_DEFI_MAXIMUM {
+                Err(format!("difficulte doit etre entre 1 et {}", DIFFICULTE_DEFI_MAXIMUM))?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Defi de preuve de travail emis pour une soumission (collection defis). Le defi est retourne au
+/// client dans une reponse signee par le domaine et ne peut etre utilise qu'une seule fois.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocDefiSoumission {
+    pub defi_id: String,
+    pub application_id: String,
+    pub difficulte: u32,
+    pub expiration: i64,
+    pub utilise: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SolutionDefi {
+    pub defi_id: String,
+    pub solution: String,
+}
+
+/// Verifie que sha256("{defi_id}:{solution}") commence par au moins `difficulte` bits a zero.
+pub fn verifier_solution_defi(defi_id: &str, solution: &str, difficulte: u32) -> bool {
+    if solution.len() > TAILLE_MAXIMUM_SOLUTION_DEFI {
+        return false
+    }
+    let hachage = sha256(format!("{}:{}", defi_id, solution).as_bytes());
+    let mut bits_zero = 0;
+    for octet in hachage.iter() {
+        if *octet == 0 {
+            bits_zero += 8;
+        } else {
+            bits_zero += octet.leading_zeros();
+            break
+        }
+    }
+    bits_zero >= difficulte
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderConfigurationSpam {
+    pub application_id: String,
+    /// None pour desactiver la detection
+    pub configuration: Option<ConfigurationSpam>,
+}
+
+/// Emetteur d'une soumission publique.
+#[derive(Clone, Debug)]
+pub struct SourceSoumission {
+    /// Fingerprint du certificat du message (cle anonyme du visiteur ou certificat du relais)
+    pub fingerprint: String,
+    /// Hachage de l'emetteur, identifie un visiteur. Sert aux limites de debit par source et au
+    /// blocage (bloquerSource). Voir calculer_hachage_source.
+    pub hachage: String,
+}
+
+/// Identifie l'emetteur d'une soumission publique ou d'une requete de formulaire.
+pub fn get_source_soumission(m: &MessageValideAction, adresse_source: Option<&String>) -> SourceSoumission {
+    match m.message.certificat.as_ref() {
+        Some(c) => {
+            let relais = c.verifier_exchanges(vec![Securite::L2Prive, Securite::L3Protege, Securite::L4Secure]);
+            SourceSoumission {
+                fingerprint: c.fingerprint.clone(),
+                hachage: calculer_hachage_source(c.fingerprint.as_str(), relais, adresse_source),
+            }
+        },
+        None => SourceSoumission {
+            fingerprint: String::from("inconnu"),
+            hachage: calculer_hachage_source("inconnu", false, None),
+        }
+    }
+}
+
+/// Hachage de l'emetteur. Un visiteur qui signe avec sa cle anonyme est identifie par le fingerprint
+/// de son certificat. Un relais (certificat de composant autorise sur 2.prive ou plus) partage un
+/// certificat entre tous les visiteurs : l'adresse du visiteur qu'il fournit (adresse_source) est
+/// alors utilisee. L'adresse fournie par un visiteur est ignoree, elle permettrait de contourner
+/// les limites.
+pub fn calculer_hachage_source(fingerprint: &str, relais: bool, adresse_source: Option<&String>) -> String {
+    let valeur = match (relais, adresse_source) {
+        (true, Some(adresse)) => format!("adresse:{}", adresse.trim()),
+        _ => format!("certificat:{}", fingerprint)
+    };
+    sha256(valeur.as_bytes()).iter().map(|o| format!("{:02x}", o)).collect()
+}
+
+/// Jeton emis avec le formulaire public pour mesurer le delai avant la soumission.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JetonFormulaire {
+    pub jeton_id: String,
+    pub date_creation: i64,
+}
+
+/// Jeton de formulaire conserve (collection jetons). Le jeton ne peut etre utilise qu'une seule fois.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocJetonFormulaire {
+    pub jeton_id: String,
+    pub application_id: String,
+    pub date_creation: i64,
+    pub expiration: i64,
+    pub utilise: bool,
+}
+
+/// Transaction de liberation de messages en quarantaine (vers la boite de reception).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionLibererMessages {
+    pub message_ids: Vec<String>,
+}
+
+/// Transaction de suppression de messages.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSupprimerMessages {
+    pub message_ids: Vec<String>,
+}
+
+/// Etat de traitement d'un message (suivi des demandes). Un message sans etat est nouveau.
+/// L'etat spam est un classement par le proprietaire, il ne met pas le message en quarantaine.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EtatMessage {
+    Nouveau,
+    EnCours,
+    Attente,
+    Resolu,
+    Spam,
+}
+
+impl EtatMessage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EtatMessage::Nouveau => "nouveau",
+            EtatMessage::EnCours => "en_cours",
+            EtatMessage::Attente => "attente",
+            EtatMessage::Resolu => "resolu",
+            EtatMessage::Spam => "spam",
+        }
+    }
+
+    /// Etats a partir desquels un message peut passer a cet etat.
+    pub fn etats_precedents(&self) -> &'static [EtatMessage] {
+        match self {
+            EtatMessage::Nouveau => &[EtatMessage::Spam],
+            EtatMessage::EnCours => &[EtatMessage::Nouveau, EtatMessage::Attente, EtatMessage::Resolu],
+            EtatMessage::Attente => &[EtatMessage::Nouveau, EtatMessage::EnCours],
+            EtatMessage::Resolu => &[EtatMessage::Nouveau, EtatMessage::EnCours, EtatMessage::Attente],
+            EtatMessage::Spam => &[EtatMessage::Nouveau, EtatMessage::EnCours, EtatMessage::Attente],
+        }
+    }
+}
+
+/// Filtre mongo sur l'etat des messages. Le filtre sur nouveau inclut les messages sans etat.
+pub fn filtre_etats_messages(etats: &[EtatMessage]) -> Bson {
+    let mut valeurs: Vec<Bson> = etats.iter().map(|e| Bson::from(e.as_str())).collect();
+    if etats.contains(&EtatMessage::Nouveau) {
+        valeurs.push(Bson::Null);
+    }
+    Bson::from(doc! { "$in": valeurs })
+}
+
+/// Transaction de changement d'etat de messages. Les messages dont l'etat courant ne permet pas
+/// la transition sont ignores.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionChangerEtatMessages {
+    pub message_ids: Vec<String>,
+    pub etat: EtatMessage,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionEtiqueterMessages {
+    pub message_ids: Vec<String>,
+    pub ajouter: Option<Vec<String>>,
+    pub retirer: Option<Vec<String>>,
+}
+
+/// Retire les espaces et les doublons des etiquettes.
+pub fn normaliser_etiquettes(etiquettes: &Vec<String>) -> Result<Vec<String>, String> {
+    if etiquettes.len() > NOMBRE_MAXIMUM_ETIQUETTES {
+        Err(format!("Trop d'etiquettes (maximum {})", NOMBRE_MAXIMUM_ETIQUETTES))?
+    }
+    let mut resultat: Vec<String> = Vec::new();
+    for etiquette in etiquettes {
+        let etiquette = etiquette.trim();
+        if etiquette.is_empty() || etiquette.chars().count() > TAILLE_MAXIMUM_ETIQUETTE {
+            Err(format!("Etiquette invalide : {}", etiquette))?
+        }
+        if ! resultat.iter().any(|e| e.as_str() == etiquette) {
+            resultat.push(etiquette.to_owned());
+        }
+    }
+    Ok(resultat)
+}
+
+/// Transaction d'assignation de messages a un collaborateur. None retire l'assignation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionAssignerMessages {
+    pub message_ids: Vec<String>,
+    /// Identificateur du collaborateur (e.g. user_id)
+    pub assigne: Option<String>,
+}
+
+/// Transaction de blocage d'une source de soumissions. Sans application_id, le blocage s'applique
+/// a toute la millegrille (delegation globale requise).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionBloquerSource {
+    pub application_id: Option<String>,
+    /// Fingerprint du certificat de l'emetteur, jeton client ou hachage de source (hachage_source
+    /// d'un message). Bloquer le fingerprint d'un relais bloque tous ses visiteurs, le hachage de
+    /// source ne bloque que le visiteur.
+    pub source: String,
+    pub raison: Option<String>,
+}
+
+impl TransactionBloquerSource {
+    pub fn valider(&self) -> Result<(), String> {
+        if self.source.trim().is_empty() || self.source.len() > TAILLE_MAXIMUM_SOURCE {
+            Err(format!("source invalide"))?
+        }
+        if let Some(r) = self.raison.as_ref() {
+            if r.len() > TAILLE_MAXIMUM_SOURCE { Err(format!("raison trop longue"))? }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionDebloquerSource {
+    pub application_id: Option<String>,
+    pub source: String,
+}
+
+/// Source bloquee (collection blocages). application_id absent pour un blocage global.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocBlocage {
+    pub application_id: Option<String>,
+    pub user_id: Option<String>,
+    pub source: String,
+    pub raison: Option<String>,
+    pub date_blocage: i64,
+}
+
+/// Transaction emise par le domaine (cedule) pour ouvrir ou fermer une application selon son horaire.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionAppliquerHoraireApplication {
+    pub application_id: String,
+    pub actif: bool,
+}
+
+/// Transaction emise par le domaine (entretien) pour purger des applications de la corbeille.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionPurgerApplications {
+    pub application_ids: Vec<String>,
+}
+
+/// Transaction emise par le domaine lorsque le compte d'un usager est supprime.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSupprimerApplicationsUsager {
+    pub user_id: String,
+}
+
+/// Contenu versionne d'une application (brouillon ou version publiee).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ContenuApplication {
+    pub nom: Option<String>,
+    /// Activation de l'application, appliquee a la publication (None : inchange)
+    pub actif: Option<bool>,
+    pub champs: Option<Vec<ChampFormulaire>>,
+    pub page: Option<PageApplication>,
+}
+
+/// Version publiee d'une application, immuable (collection mongo)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocVersionApplication {
+    pub application_id: String,
+    pub user_id: String,
+    pub version: i64,
+    pub contenu: ContenuApplication,
+    pub date_publication: i64,
+    /// Version restauree, si la publication provient d'un retour arriere
+    pub version_source: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionPublierApplication {
+    pub application_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionRestaurerVersionApplication {
+    pub application_id: String,
+    pub version: i64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,18 +558,496 @@ pub struct TransactionSauvegarderApplication {
     pub application_id: String,
     pub nom: Option<String>,
     pub actif: Option<bool>,
+    pub champs: Option<Vec<ChampFormulaire>>,
+}
+
+/// Information publique d'une application (formulaire a afficher aux visiteurs).
+/// Ne doit jamais contenir le user_id ou d'autres champs internes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApplicationPublique {
+    pub application_id: String,
+    pub nom: Option<String>,
+    pub version: Option<i64>,
+    pub ouvert: bool,
+    pub date_ouverture: Option<i64>,
+    pub date_fermeture: Option<i64>,
+    /// Nombre de soumissions restantes pour la periode courante (None si aucune limite)
+    pub places_restantes: Option<i64>,
+    /// Difficulte du defi (getDefiSoumission) a resoudre avant de soumettre
+    pub difficulte_defi: Option<u32>,
+    /// Jeton a retourner avec la soumission (detection de spam)
+    pub jeton_formulaire: Option<JetonFormulaire>,
+    /// Champs caches a ajouter au formulaire
+    pub champs_pieges: Option<Vec<String>>,
+    /// Les messages sont transferes vers Messagerie, fournir _commandeMaitreclesMessagerie
+    pub transfert_messagerie: bool,
+    pub limites_fichiers: LimitesFichiers,
+    pub champs: Option<Vec<ChampFormulaire>>,
+    pub page: Option<PageApplication>,
+}
+
+impl From<DocApplication> for ApplicationPublique {
+    fn from(value: DocApplication) -> Self {
+        let ouvert = value.actif == Some(true) && value.est_ouvert_horaire(&Utc::now()) != Some(false);
+        Self {
+            application_id: value.application_id,
+            nom: value.nom,
+            version: value.version_publiee,
+            ouvert,
+            date_ouverture: value.date_ouverture,
+            date_fermeture: value.date_fermeture,
+            places_restantes: None,
+            difficulte_defi: value.difficulte_defi,
+            jeton_formulaire: None,
+            champs_pieges: value.configuration_spam.and_then(|c| c.champs_pieges),
+            transfert_messagerie: value.transfert_messagerie == Some(true),
+            limites_fichiers: value.limites_fichiers.unwrap_or_default(),
+            champs: value.champs,
+            page: value.page,
+        }
+    }
+}
+
+/// Contenu de la page web d'une application.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PageApplication {
+    pub titre: Option<String>,
+    /// Corps de la page en markdown
+    pub contenu: Option<String>,
+    pub logo_fuuid: Option<String>,
+    pub banniere_fuuid: Option<String>,
+    /// Message affiche apres la soumission
+    pub message_remerciement: Option<String>,
+    /// Url de redirection apres la soumission
+    pub url_redirection: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderPageApplication {
+    pub application_id: String,
+    pub page: PageApplication,
+}
+
+/// Transaction de suppression d'une application (corbeille).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSupprimerApplication {
+    pub application_id: String,
+}
+
+/// Transaction de restauration d'une application qui est dans la corbeille.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionRestaurerApplication {
+    pub application_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionArchiverApplication {
+    pub application_id: String,
+    /// false pour retirer l'application des archives
+    pub archive: Option<bool>,
+}
+
+/// Type de champ du formulaire d'une application
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeChamp {
+    Texte,
+    Multiligne,
+    Courriel,
+    Nombre,
+    Choix,
+    Case,
+    Fichier,
+}
+
+/// Champ du formulaire d'une application
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChampFormulaire {
+    pub nom_champ: String,
+    pub code_interne: String,
+    pub type_champ: TypeChamp,
+    pub libelle: Option<String>,
+    pub aide: Option<String>,
+    pub ordre: Option<i32>,
+    pub taille_maximum: Option<i32>,
+    pub requis: Option<bool>,
+    /// Valeurs permises pour un champ de type choix
+    pub choix: Option<Vec<String>>,
+}
+
+/// Verifie la coherence des champs d'un formulaire (commande seulement, les transactions deja
+/// conservees ne sont pas validees de nouveau). Retourne les champs tries selon l'ordre.
+pub fn valider_champs_formulaire(champs: &Vec<ChampFormulaire>) -> Result<Vec<ChampFormulaire>, String> {
+    let mut codes = HashSet::new();
+    for champ in champs {
+        if champ.code_interne.is_empty() {
+            Err(format!("Champ {} sans code_interne", champ.nom_champ))?
+        }
+        if ! codes.insert(champ.code_interne.as_str()) {
+            Err(format!("Code interne {} duplique", champ.code_interne))?
+        }
+        if let Some(t) = champ.taille_maximum {
+            if t <= 0 { Err(format!("Champ {} taille_maximum invalide", champ.code_interne))? }
+        }
+        match champ.type_champ {
+            TypeChamp::Choix => match champ.choix.as_ref() {
+                Some(c) if ! c.is_empty() => (),
+                _ => Err(format!("Champ {} de type choix sans valeurs", champ.code_interne))?
+            },
+            _ => if champ.choix.is_some() {
+                Err(format!("Champ {} : valeurs de choix permises uniquement pour le type choix", champ.code_interne))?
+            }
+        }
+        if champ.type_champ == TypeChamp::Courriel {
+            if let Some(t) = champ.taille_maximum {
+                if t > TAILLE_MAXIMUM_COURRIEL {
+                    Err(format!("Champ {} taille_maximum superieure a {} pour un courriel", champ.code_interne, TAILLE_MAXIMUM_COURRIEL))?
+                }
+            }
+        }
+    }
+
+    Ok(trier_champs_formulaire(champs))
+}
+
+/// Trie les champs d'un formulaire selon l'ordre (sans validation).
+pub fn trier_champs_formulaire(champs: &Vec<ChampFormulaire>) -> Vec<ChampFormulaire> {
+    let mut champs_tries = champs.clone();
+    champs_tries.sort_by_key(|c| c.ordre.unwrap_or(i32::MAX));
+    champs_tries
+}
+
+/// Verifie le format d'une adresse courriel : une seule @, partie locale et domaine non vides, sans
+/// espaces. La verification complete se fait a l'envoi.
+fn est_courriel_valide(valeur: &str) -> bool {
+    let mut parties = valeur.split('@');
+    match (parties.next(), parties.next(), parties.next()) {
+        (Some(local), Some(domaine), None) => {
+            ! local.is_empty() && ! domaine.is_empty() && domaine.contains('.')
+                && ! domaine.starts_with('.') && ! domaine.ends_with('.')
+                && ! valeur.chars().any(|c| c.is_whitespace() || c.is_control())
+        },
+        _ => false
+    }
+}
+
+fn est_nombre_valide(valeur: &str) -> bool {
+    match valeur.trim().parse::<f64>() {
+        Ok(n) => n.is_finite(),
+        Err(_) => false
+    }
+}
+
+/// Commande/Transaction de soumission d'un message (public) vers une application.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSoumettreMessage {
+    pub application_id: String,
+    pub data_chiffre: String,
+    pub format: FormatChiffrage,
+    pub header: String,
+    pub ref_hachage_bytes: String,
+    pub enveloppe: Option<EnveloppeMessage>,
+    /// Solution du defi (getDefiSoumission) lorsque l'application l'exige
+    pub defi: Option<SolutionDefi>,
+    /// Jeton recu avec le formulaire (getApplicationPublique)
+    pub jeton_formulaire: Option<JetonFormulaire>,
+    /// Valeurs des champs caches (pieges) du formulaire
+    pub pieges: Option<HashMap<String, String>>,
+    /// Jeton persistant du client (navigateur), permet de bloquer un visiteur
+    pub jeton_client: Option<String>,
+    /// Adresse du visiteur (ou son hachage) ajoutee par un relais, voir calculer_hachage_source
+    pub adresse_source: Option<String>,
+    /// Fichiers joints, deja televerses (chiffres) vers le service de fichiers
+    pub fichiers: Option<Vec<FichierMessage>>,
+    #[serde(rename="_commandeMaitrecles", skip_serializing_if = "Option::is_none")]
+    pub commande_maitredescles: Option<CommandeSauvegarderCle>,
+    /// Meme cle secrete, sauvegardee pour le domaine Messagerie lors du transfert. Fournie par le
+    /// client lorsque l'application transfere les messages (transfert_messagerie).
+    #[serde(rename="_commandeMaitreclesMessagerie", skip_serializing_if = "Option::is_none")]
+    pub commande_maitredescles_messagerie: Option<CommandeSauvegarderCle>,
+}
+
+/// Information non chiffree qui accompagne un message soumis.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnveloppeMessage {
+    /// Taille totale du contenu avant chiffrage
+    pub taille: i64,
+    pub champs: Vec<EnveloppeChamp>,
+    pub nombre_fichiers: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnveloppeChamp {
+    pub code_interne: String,
+    pub taille: i64,
+    /// Valeur en clair, requise uniquement pour un champ de type choix. Le format d'une valeur
+    /// courriel ou nombre fournie en clair est verifie.
+    pub valeur: Option<String>,
+}
+
+/// Fichier joint a un message. Le fichier est chiffre avec la cle du message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FichierMessage {
+    pub fuuid: String,
+    /// Taille du fichier chiffre
+    pub taille: i64,
+    pub mimetype: String,
+    /// Champ (type fichier) du formulaire
+    pub code_interne: Option<String>,
+    /// Header de dechiffrage du fichier
+    pub header: Option<String>,
+}
+
+/// Limites des fichiers joints aux messages d'une application.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LimitesFichiers {
+    pub nombre_maximum: u32,
+    /// Taille totale (bytes) des fichiers d'un message
+    pub taille_totale_maximum: i64,
+    /// Types mime permis, e.g. "application/pdf" ou "image/*". Absent : tous les types.
+    pub mimetypes: Option<Vec<String>>,
+}
+
+impl Default for LimitesFichiers {
+    fn default() -> Self {
+        Self { nombre_maximum: FICHIERS_NOMBRE_DEFAUT, taille_totale_maximum: FICHIERS_TAILLE_TOTALE_DEFAUT, mimetypes: None }
+    }
+}
+
+impl LimitesFichiers {
+    pub fn valider(&self) -> Result<(), String> {
+        if self.nombre_maximum > FICHIERS_NOMBRE_MAXIMUM {
+            Err(format!("nombre_maximum doit etre au plus {}", FICHIERS_NOMBRE_MAXIMUM))?
+        }
+        if self.taille_totale_maximum < 0 || self.taille_totale_maximum > FICHIERS_TAILLE_TOTALE_MAXIMUM {
+            Err(format!("taille_totale_maximum invalide"))?
+        }
+        if let Some(mimetypes) = self.mimetypes.as_ref() {
+            if mimetypes.iter().any(|m| m.is_empty() || m.len() > TAILLE_MAXIMUM_MIMETYPE || ! m.contains('/')) {
+                Err(format!("mimetypes invalides"))?
+            }
+        }
+        Ok(())
+    }
+
+    fn mimetype_permis(&self, mimetype: &str) -> bool {
+        let mimetypes = match self.mimetypes.as_ref() {
+            Some(inner) => inner,
+            None => return true
+        };
+        let mimetype = mimetype.to_lowercase();
+        mimetypes.iter().any(|m| {
+            let m = m.to_lowercase();
+            match m.strip_suffix("/*") {
+                Some(prefixe) => mimetype.split('/').next() == Some(prefixe),
+                None => m == mimetype
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderLimitesFichiers {
+    pub application_id: String,
+    /// None pour utiliser les limites par defaut
+    pub limites: Option<LimitesFichiers>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderTransfertMessagerie {
+    pub application_id: String,
+    pub actif: bool,
+}
+
+/// Etat d'un fichier (collection fichiers) selon les evenements de consignation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtatFichier {
+    Attente,
+    Consigne,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocFichier {
+    pub fuuid: String,
+    pub etat: EtatFichier,
+    pub date_consigne: Option<i64>,
+    /// true lorsque le fichier a ete joint a un message. Seuls ces fichiers sont liberes par la
+    /// collecte, les autres sont des fichiers consignes pour d'autres domaines.
+    pub reference: Option<bool>,
+}
+
+/// Champs des documents qui font reference a des fuuids, par collection.
+const REFERENCES_FUUIDS: &[(&str, &[&str])] = &[
+    (NOM_COLLECTION_MESSAGES, &[CHAMP_FUUIDS]),
+    (NOM_COLLECTION_APPLICATIONS, &["page.logo_fuuid", "page.banniere_fuuid", "brouillon.page.logo_fuuid", "brouillon.page.banniere_fuuid"]),
+    (NOM_COLLECTION_VERSIONS, &["contenu.page.logo_fuuid", "contenu.page.banniere_fuuid"]),
+];
+
+/// Retourne les fuuids (parmi ceux recus) qui sont encore references par un message, une
+/// application ou une version d'application.
+pub async fn trouver_fuuids_references<M>(middleware: &M, fuuids: &Vec<String>) -> Result<HashSet<String>, String>
+    where M: MongoDao
+{
+    let mut references = HashSet::new();
+    if fuuids.is_empty() {
+        return Ok(references)
+    }
+
+    for (nom_collection, champs) in REFERENCES_FUUIDS {
+        let collection = middleware.get_collection(nom_collection)?;
+        for champ in champs.iter() {
+            let champ: &str = champ;
+            let filtre = doc! { champ: {"$in": fuuids} };
+            let valeurs = match collection.distinct(champ, filtre, None).await {
+                Ok(inner) => inner,
+                Err(e) => Err(format!("common.trouver_fuuids_references Erreur chargement {} : {:?}", nom_collection, e))?
+            };
+            references.extend(valeurs.into_iter().filter_map(|v| v.as_str().map(|f| f.to_owned())));
+        }
+    }
+
+    // distinct sur un tableau retourne toutes les valeurs du tableau, conserver les fuuids demandes
+    references.retain(|f| fuuids.contains(f));
+    Ok(references)
+}
+
+/// Retourne les fuuids references par les messages, les applications et les versions des
+/// applications recues.
+pub async fn charger_fuuids_applications<M>(middleware: &M, application_ids: &Vec<String>) -> Result<Vec<String>, String>
+    where M: MongoDao
+{
+    let mut fuuids = HashSet::new();
+    if application_ids.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    let filtre = doc! { CHAMP_APPLICATION_ID: {"$in": application_ids} };
+    for (nom_collection, champs) in REFERENCES_FUUIDS {
+        let collection = middleware.get_collection(nom_collection)?;
+        for champ in champs.iter() {
+            let champ: &str = champ;
+            let valeurs = match collection.distinct(champ, filtre.clone(), None).await {
+                Ok(inner) => inner,
+                Err(e) => Err(format!("common.charger_fuuids_applications Erreur chargement {} : {:?}", nom_collection, e))?
+            };
+            fuuids.extend(valeurs.into_iter().filter_map(|v| v.as_str().map(|f| f.to_owned())));
+        }
+    }
+
+    Ok(fuuids.into_iter().collect())
+}
+
+/// Verifie les fichiers joints avec les limites de l'application et les champs du formulaire.
+/// Appeler aussi sans fichiers joints (slice vide) pour verifier le nombre_fichiers declare.
+pub fn valider_fichiers_message(limites: &LimitesFichiers, champs: Option<&Vec<ChampFormulaire>>,
+                                enveloppe: Option<&EnveloppeMessage>, fichiers: &[FichierMessage])
+    -> Result<(), Vec<ErreurChamp>>
+{
+    let mut erreurs = Vec::new();
+
+    if fichiers.len() > limites.nombre_maximum as usize {
+        erreurs.push(ErreurChamp::new("fichiers", format!("maximum {} fichiers", limites.nombre_maximum)));
+    }
+    if let Some(nombre) = enveloppe.and_then(|e| e.nombre_fichiers) {
+        if nombre < 0 || nombre as usize != fichiers.len() {
+            erreurs.push(ErreurChamp::new("fichiers", "nombre_fichiers ne correspond pas aux fichiers joints"));
+        }
+    }
+
+    let taille_totale: i64 = fichiers.iter().map(|f| f.taille).sum();
+    if taille_totale > limites.taille_totale_maximum {
+        erreurs.push(ErreurChamp::new("fichiers", format!("taille totale maximum {} bytes", limites.taille_totale_maximum)));
+    }
+
+    let champs_fichiers: HashSet<&str> = champs.map(|c| c.iter()
+        .filter(|c| c.type_champ == TypeChamp::Fichier)
+        .map(|c| c.code_interne.as_str())
+        .collect()
+    ).unwrap_or_default();
+
+    if ! fichiers.is_empty() && champs_fichiers.is_empty() {
+        erreurs.push(ErreurChamp::new("fichiers", "le formulaire n'accepte pas de fichiers"));
+    }
+
+    let mut fuuids = HashSet::new();
+    for fichier in fichiers {
+        let code = fichier.code_interne.as_ref().map(|c| c.as_str()).unwrap_or("fichiers");
+        if fichier.fuuid.is_empty() || fichier.fuuid.len() > TAILLE_MAXIMUM_FUUID {
+            erreurs.push(ErreurChamp::new(code, "fuuid invalide"));
+        } else if ! fuuids.insert(fichier.fuuid.as_str()) {
+            erreurs.push(ErreurChamp::new(code, format!("fuuid {} en double", fichier.fuuid)));
+        }
+        if fichier.taille < 0 {
+            erreurs.push(ErreurChamp::new(code, "taille invalide"));
+        }
+        if fichier.mimetype.len() > TAILLE_MAXIMUM_MIMETYPE || ! limites.mimetype_permis(fichier.mimetype.as_str()) {
+            erreurs.push(ErreurChamp::new(code, format!("type {} non permis", fichier.mimetype)));
+        }
+        if let Some(c) = fichier.code_interne.as_ref() {
+            if ! champs_fichiers.contains(c.as_str()) {
+                erreurs.push(ErreurChamp::new(code, "champ fichier inconnu"));
+            }
+        }
+    }
+
+    match erreurs.is_empty() {
+        true => Ok(()),
+        false => Err(erreurs)
+    }
+}
+
+/// Erreur de validation pour un champ d'un message soumis
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErreurChamp {
+    pub code_interne: String,
+    pub erreur: String,
+}
+
+impl ErreurChamp {
+    fn new<S: Into<String>, E: Into<String>>(code_interne: S, erreur: E) -> Self {
+        Self { code_interne: code_interne.into(), erreur: erreur.into() }
+    }
+}
+
+/// Message recu pour une application (collection mongo)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocMessage {
+    pub message_id: String,
+    pub application_id: String,
+    pub user_id: String,
+    pub data_chiffre: String,
+    pub format: FormatChiffrage,
+    pub header: String,
+    pub ref_hachage_bytes: String,
+    pub enveloppe: Option<EnveloppeMessage>,
+    pub date_reception: i64,
+    /// Version publiee de l'application lors de la soumission
+    pub version_application: Option<i64>,
+    pub lu: Option<bool>,
+    pub hachage_contenu: Option<String>,
+    /// Fingerprint du certificat de l'emetteur, peut etre bloque (bloquerSource)
+    pub source: Option<String>,
+    /// Hachage de l'emetteur (calculer_hachage_source), peut etre bloque (bloquerSource)
+    pub hachage_source: Option<String>,
+    pub jeton_client: Option<String>,
+    /// Message retenu par la detection de spam, absent de la boite de reception
+    pub quarantaine: Option<bool>,
+    pub spam: Option<ResultatSpam>,
+    pub fichiers: Option<Vec<FichierMessage>>,
+    pub fuuids: Option<Vec<String>>,
+    pub fuuids_consignes: Option<Vec<String>>,
+    /// Fichiers joints qui ne sont pas encore consignes
+    pub fichiers_manquants: Option<Vec<String>>,
+    /// None : nouveau
+    pub etat: Option<EtatMessage>,
+    pub date_etat: Option<i64>,
+    pub etiquettes: Option<Vec<String>>,
+    pub assigne: Option<String>,
+    /// Commande de cle pour le domaine Messagerie, emise lors du transfert
+    pub cle_messagerie: Option<CommandeSauvegarderCle>,
 }
 
-// /// Champ d'une categorie
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// pub struct ChampCategorie {
-//     pub nom_champ: String,
-//     pub code_interne: String,
-//     pub type_champ: String,
-//     pub taille_maximum: Option<i32>,
-//     pub requis: Option<bool>,
-// }
-//
 // #[derive(Clone, Debug, Serialize, Deserialize)]
 // pub struct TransactionSauvegarderGroupeUsager {
 //     pub groupe_id: Option<String>,
@@ -74,3 +1089,521 @@ pub struct TransactionSauvegarderApplication {
 //     pub format: FormatChiffrage,
 //     pub header: String,
 // }
+
+/// Verifie l'enveloppe d'un message soumis avec les champs du formulaire de l'application.
+/// Retourne la liste de toutes les erreurs detectees.
+pub fn valider_enveloppe_message(champs: &Vec<ChampFormulaire>, enveloppe: &EnveloppeMessage)
+    -> Result<(), Vec<ErreurChamp>>
+{
+    let mut erreurs = Vec::new();
+
+    let champs_formulaire: HashMap<&str, &ChampFormulaire> = champs.iter()
+        .map(|c| (c.code_interne.as_str(), c))
+        .collect();
+    let mut champs_presents = HashSet::new();
+
+    for champ_enveloppe in &enveloppe.champs {
+        let code = champ_enveloppe.code_interne.as_str();
+        if ! champs_presents.insert(code) {
+            erreurs.push(ErreurChamp::new(code, "duplique"));
+            continue
+        }
+        let champ = match champs_formulaire.get(code) {
+            Some(inner) => *inner,
+            None => {
+                erreurs.push(ErreurChamp::new(code, "inconnu"));
+                continue
+            }
+        };
+        if let Some(taille_maximum) = champ.taille_maximum {
+            if champ_enveloppe.taille > taille_maximum as i64 {
+                erreurs.push(ErreurChamp::new(code, format!("taille_maximum {} depassee", taille_maximum)));
+            }
+        }
+        match champ.type_champ {
+            TypeChamp::Choix => {
+                let valeurs_permises = champ.choix.as_ref();
+                match (champ_enveloppe.valeur.as_ref(), valeurs_permises) {
+                    (Some(v), Some(permises)) if permises.contains(v) => (),
+                    _ => erreurs.push(ErreurChamp::new(code, "choix invalide")),
+                }
+            },
+            TypeChamp::Courriel => {
+                if champ_enveloppe.taille > TAILLE_MAXIMUM_COURRIEL as i64 {
+                    erreurs.push(ErreurChamp::new(code, "courriel trop long"));
+                } else if let Some(v) = champ_enveloppe.valeur.as_ref() {
+                    if ! est_courriel_valide(v.as_str()) {
+                        erreurs.push(ErreurChamp::new(code, "courriel invalide"));
+                    }
+                }
+            },
+            TypeChamp::Nombre => {
+                if let Some(v) = champ_enveloppe.valeur.as_ref() {
+                    if ! est_nombre_valide(v.as_str()) {
+                        erreurs.push(ErreurChamp::new(code, "nombre invalide"));
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    for champ in champs {
+        if champ.requis == Some(true) && ! champs_presents.contains(champ.code_interne.as_str()) {
+            erreurs.push(ErreurChamp::new(champ.code_interne.as_str(), "requis"));
+        }
+    }
+
+    // Le nombre de fichiers est verifie avec les fichiers joints (valider_fichiers_message)
+
+    let taille_champs: i64 = enveloppe.champs.iter().map(|c| c.taille).sum();
+    if taille_champs > enveloppe.taille {
+        erreurs.push(ErreurChamp::new("taille", "taille des champs superieure a la taille du message"));
+    }
+
+    match erreurs.is_empty() {
+        true => Ok(()),
+        false => Err(erreurs)
+    }
+}
+
+/// Verifie le contenu et la taille des elements de la page d'une application.
+pub fn valider_page_application(page: &PageApplication) -> Result<(), String> {
+    fn valider_taille(nom: &str, valeur: &Option<String>, taille_maximum: usize) -> Result<(), String> {
+        if let Some(v) = valeur.as_ref() {
+            if v.chars().count() > taille_maximum {
+                Err(format!("{} depasse la taille maximum de {}", nom, taille_maximum))?
+            }
+        }
+        Ok(())
+    }
+
+    valider_taille("titre", &page.titre, TAILLE_MAXIMUM_PAGE_TITRE)?;
+    valider_taille("contenu", &page.contenu, TAILLE_MAXIMUM_PAGE_CONTENU)?;
+    valider_taille("message_remerciement", &page.message_remerciement, TAILLE_MAXIMUM_PAGE_REMERCIEMENT)?;
+    valider_taille("url_redirection", &page.url_redirection, TAILLE_MAXIMUM_PAGE_URL)?;
+
+    for (nom, fuuid) in [("logo_fuuid", &page.logo_fuuid), ("banniere_fuuid", &page.banniere_fuuid)] {
+        if let Some(f) = fuuid.as_ref() {
+            if f.is_empty() || f.len() > TAILLE_MAXIMUM_FUUID || ! f.chars().all(|c| c.is_ascii_alphanumeric()) {
+                Err(format!("{} invalide", nom))?
+            }
+        }
+    }
+
+    if let Some(url) = page.url_redirection.as_ref() {
+        if ! url.starts_with("https://") && ! url.starts_with("http://") {
+            Err(format!("url_redirection doit etre une url http(s)"))?
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_common {
+    use super::*;
+    use millegrilles_common_rust::chrono::TimeZone;
+    use millegrilles_common_rust::serde_json::{from_value, json};
+    use crate::test_setup::setup;
+
+    /// 2023-01-02 est un lundi (jour 0).
+    fn date(jour: u32, heure: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 1, 2 + jour, heure, minute, 0).unwrap()
+    }
+
+    fn horaire(jours: Vec<u32>, minute_ouverture: u32, minute_fermeture: u32) -> HoraireApplication {
+        HoraireApplication { jours, minute_ouverture, minute_fermeture, decalage_utc_minutes: None }
+    }
+
+    fn application(date_ouverture: Option<i64>, date_fermeture: Option<i64>, horaire: Option<HoraireApplication>) -> DocApplication {
+        let mut app: DocApplication = from_value(json!({"user_id": "usager", "application_id": "app"}))
+            .expect("application");
+        app.date_ouverture = date_ouverture;
+        app.date_fermeture = date_fermeture;
+        app.horaire = horaire;
+        app
+    }
+
+    #[test]
+    fn test_horaire_jour() {
+        setup("test_horaire_jour");
+        // Lundi et mardi, 9h a 17h
+        let h = horaire(vec![0, 1], 9 * 60, 17 * 60);
+        assert!(h.valider().is_ok());
+        assert!(! h.est_ouvert(&date(0, 8, 59)));
+        assert!(h.est_ouvert(&date(0, 9, 0)));
+        assert!(h.est_ouvert(&date(1, 16, 59)));
+        assert!(! h.est_ouvert(&date(1, 17, 0)));
+        // Mercredi ferme
+        assert!(! h.est_ouvert(&date(2, 12, 0)));
+    }
+
+    #[test]
+    fn test_horaire_nuit() {
+        setup("test_horaire_nuit");
+        // Vendredi 22h au samedi 6h
+        let h = horaire(vec![4], 22 * 60, 6 * 60);
+        assert!(h.valider().is_ok());
+        assert!(! h.est_ouvert(&date(4, 21, 59)));
+        assert!(h.est_ouvert(&date(4, 22, 0)));
+        assert!(h.est_ouvert(&date(4, 23, 59)));
+        // Apres minuit, le jour precedent (vendredi) est le jour d'ouverture
+        assert!(h.est_ouvert(&date(5, 0, 0)));
+        assert!(h.est_ouvert(&date(5, 5, 59)));
+        assert!(! h.est_ouvert(&date(5, 6, 0)));
+        // Samedi soir n'est pas un jour d'ouverture
+        assert!(! h.est_ouvert(&date(5, 23, 0)));
+        // Jeudi apres minuit (mercredi n'est pas ouvert)
+        assert!(! h.est_ouvert(&date(3, 1, 0)));
+    }
+
+    #[test]
+    fn test_horaire_nuit_dimanche_lundi() {
+        setup("test_horaire_nuit_dimanche_lundi");
+        // Dimanche 23h au lundi 1h : le jour precedent de lundi est dimanche
+        let h = horaire(vec![6], 23 * 60, 60);
+        assert!(h.est_ouvert(&date(6, 23, 30)));
+        assert!(h.est_ouvert(&date(7, 0, 30)));
+        assert!(! h.est_ouvert(&date(7, 1, 0)));
+        assert!(! h.est_ouvert(&date(6, 22, 59)));
+    }
+
+    #[test]
+    fn test_horaire_decalage_utc() {
+        setup("test_horaire_decalage_utc");
+        // Lundi 9h a 17h heure locale UTC-5
+        let mut h = horaire(vec![0], 9 * 60, 17 * 60);
+        h.decalage_utc_minutes = Some(-300);
+        assert!(! h.est_ouvert(&date(0, 9, 0)));
+        assert!(h.est_ouvert(&date(0, 14, 0)));
+        // Lundi 21h59 UTC = 16h59 locale, mardi 0h UTC = lundi 19h locale
+        assert!(h.est_ouvert(&date(0, 21, 59)));
+        assert!(! h.est_ouvert(&date(1, 0, 0)));
+    }
+
+    #[test]
+    fn test_horaire_valider() {
+        setup("test_horaire_valider");
+        assert!(horaire(vec![], 0, 60).valider().is_err());
+        assert!(horaire(vec![7], 0, 60).valider().is_err());
+        assert!(horaire(vec![0], 24 * 60, 60).valider().is_err());
+        assert!(horaire(vec![0], 0, 24 * 60).valider().is_err());
+        assert!(horaire(vec![0], 60, 60).valider().is_err());
+        let mut h = horaire(vec![0], 0, 60);
+        h.decalage_utc_minutes = Some(15 * 60);
+        assert!(h.valider().is_err());
+        h.decalage_utc_minutes = Some(-14 * 60);
+        assert!(h.valider().is_ok());
+    }
+
+    #[test]
+    fn test_transaction_horaire_valider_dates() {
+        setup("test_transaction_horaire_valider_dates");
+        let transaction = |date_ouverture, date_fermeture| TransactionSauvegarderHoraireApplication {
+            application_id: String::from("app"), date_ouverture, date_fermeture, horaire: None };
+        assert!(transaction(None, None).valider().is_ok());
+        assert!(transaction(Some(100), None).valider().is_ok());
+        assert!(transaction(None, Some(100)).valider().is_ok());
+        assert!(transaction(Some(100), Some(200)).valider().is_ok());
+        assert!(transaction(Some(200), Some(200)).valider().is_err());
+        assert!(transaction(Some(200), Some(100)).valider().is_err());
+    }
+
+    #[test]
+    fn test_est_ouvert_horaire_dates() {
+        setup("test_est_ouvert_horaire_dates");
+        let midi = date(0, 12, 0);
+        let ts = midi.timestamp();
+
+        assert_eq!(None, application(None, None, None).est_ouvert_horaire(&midi));
+
+        // Ouverture seulement
+        assert_eq!(Some(true), application(Some(ts), None, None).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(Some(ts + 1), None, None).est_ouvert_horaire(&midi));
+
+        // Fermeture seulement, la date de fermeture est exclue
+        assert_eq!(Some(true), application(None, Some(ts + 1), None).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(None, Some(ts), None).est_ouvert_horaire(&midi));
+
+        // Ouverture et fermeture
+        assert_eq!(Some(true), application(Some(ts - 10), Some(ts + 10), None).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(Some(ts + 10), Some(ts + 20), None).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(Some(ts - 20), Some(ts - 10), None).est_ouvert_horaire(&midi));
+    }
+
+    #[test]
+    fn test_est_ouvert_horaire_dates_et_horaire() {
+        setup("test_est_ouvert_horaire_dates_et_horaire");
+        let midi = date(0, 12, 0);
+        let ts = midi.timestamp();
+        let lundi = horaire(vec![0], 9 * 60, 17 * 60);
+        let mardi = horaire(vec![1], 9 * 60, 17 * 60);
+
+        assert_eq!(Some(true), application(None, None, Some(lundi.clone())).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(None, None, Some(mardi)).est_ouvert_horaire(&midi));
+        // L'horaire ne s'applique qu'entre les dates
+        assert_eq!(Some(true), application(Some(ts - 10), Some(ts + 10), Some(lundi.clone())).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(Some(ts + 10), None, Some(lundi.clone())).est_ouvert_horaire(&midi));
+        assert_eq!(Some(false), application(None, Some(ts), Some(lundi)).est_ouvert_horaire(&midi));
+    }
+
+    /// Cherche une solution dont le hachage a exactement `bits` bits a zero au debut.
+    fn trouver_solution(defi_id: &str, bits: u32) -> String {
+        (0..1_000_000u32).map(|i| i.to_string())
+            .find(|solution| {
+                let hachage = sha256(format!("{}:{}", defi_id, solution).as_bytes());
+                let mut premiers = [0u8; 16];
+                premiers.copy_from_slice(&hachage[..16]);
+                u128::from_be_bytes(premiers).leading_zeros() == bits
+            })
+            .expect("solution")
+    }
+
+    #[test]
+    fn test_solution_defi_difficulte_0() {
+        setup("test_solution_defi_difficulte_0");
+        let solution = trouver_solution("defi", 0);
+        assert!(verifier_solution_defi("defi", solution.as_str(), 0));
+        assert!(! verifier_solution_defi("defi", solution.as_str(), 1));
+    }
+
+    #[test]
+    fn test_solution_defi_frontiere_octet() {
+        setup("test_solution_defi_frontiere_octet");
+        // 7 bits : premier octet a 1
+        let solution = trouver_solution("defi", 7);
+        assert!(verifier_solution_defi("defi", solution.as_str(), 7));
+        assert!(! verifier_solution_defi("defi", solution.as_str(), 8));
+
+        // 8 bits : premier octet a 0, bit de poids fort du deuxieme octet a 1
+        let solution = trouver_solution("defi", 8);
+        assert!(verifier_solution_defi("defi", solution.as_str(), 0));
+        assert!(verifier_solution_defi("defi", solution.as_str(), 8));
+        assert!(! verifier_solution_defi("defi", solution.as_str(), 9));
+
+        // 9 bits : le compte continue dans le deuxieme octet
+        let solution = trouver_solution("defi", 9);
+        assert!(verifier_solution_defi("defi", solution.as_str(), 8));
+        assert!(verifier_solution_defi("defi", solution.as_str(), 9));
+        assert!(! verifier_solution_defi("defi", solution.as_str(), 10));
+    }
+
+    #[test]
+    fn test_solution_defi_trop_longue() {
+        setup("test_solution_defi_trop_longue");
+        let solution = "0".repeat(TAILLE_MAXIMUM_SOLUTION_DEFI + 1);
+        assert!(! verifier_solution_defi("defi", solution.as_str(), 0));
+    }
+
+    fn transition_permise(de: EtatMessage, vers: EtatMessage) -> bool {
+        vers.etats_precedents().contains(&de)
+    }
+
+    #[test]
+    fn test_etats_transitions_permises() {
+        setup("test_etats_transitions_permises");
+        use EtatMessage::*;
+        let permises = [
+            (Nouveau, EnCours), (Nouveau, Attente), (Nouveau, Resolu), (Nouveau, Spam),
+            (EnCours, Attente), (EnCours, Resolu), (EnCours, Spam),
+            (Attente, EnCours), (Attente, Resolu), (Attente, Spam),
+            (Resolu, EnCours),
+            (Spam, Nouveau),
+        ];
+        let etats = [Nouveau, EnCours, Attente, Resolu, Spam];
+        for de in etats {
+            for vers in etats {
+                let attendu = permises.contains(&(de, vers));
+                assert_eq!(attendu, transition_permise(de, vers), "transition {:?} -> {:?}", de, vers);
+            }
+        }
+    }
+
+    #[test]
+    fn test_etats_transitions_interdites() {
+        setup("test_etats_transitions_interdites");
+        use EtatMessage::*;
+        // Aucune transition vers le meme etat
+        for etat in [Nouveau, EnCours, Attente, Resolu, Spam] {
+            assert!(! transition_permise(etat, etat), "transition {:?} -> {:?}", etat, etat);
+        }
+        // Un message resolu doit etre repris (en_cours) avant d'etre mis en attente ou classe spam
+        assert!(! transition_permise(Resolu, Attente));
+        assert!(! transition_permise(Resolu, Spam));
+        // Un spam revient seulement a nouveau
+        assert!(! transition_permise(Spam, EnCours));
+        assert!(! transition_permise(Spam, Resolu));
+    }
+
+    #[test]
+    fn test_etat_serialisation() {
+        setup("test_etat_serialisation");
+        assert_eq!(json!("en_cours"), json!(EtatMessage::EnCours));
+        let etat: EtatMessage = from_value(json!("en_cours")).unwrap();
+        assert_eq!(EtatMessage::EnCours, etat);
+        assert_eq!("en_cours", EtatMessage::EnCours.as_str());
+    }
+
+    #[test]
+    fn test_filtre_etats_nouveau_sans_etat() {
+        setup("test_filtre_etats_nouveau_sans_etat");
+        let filtre = filtre_etats_messages(&[EtatMessage::Nouveau]);
+        assert_eq!(Bson::from(doc! {"$in": [Bson::from("nouveau"), Bson::Null]}), filtre);
+        let filtre = filtre_etats_messages(&[EtatMessage::Resolu]);
+        assert_eq!(Bson::from(doc! {"$in": [Bson::from("resolu")]}), filtre);
+    }
+
+    #[test]
+    fn test_normaliser_etiquettes() {
+        setup("test_normaliser_etiquettes");
+        let etiquettes = vec![
+            String::from(" urgent "), String::from("client"), String::from("urgent"), String::from("Client"),
+        ];
+        // Espaces retires, doublons exacts retires, l'ordre et la casse sont conserves
+        assert_eq!(vec!["urgent", "client", "Client"], normaliser_etiquettes(&etiquettes).unwrap());
+        assert_eq!(Vec::<String>::new(), normaliser_etiquettes(&vec![]).unwrap());
+    }
+
+    #[test]
+    fn test_normaliser_etiquettes_invalides() {
+        setup("test_normaliser_etiquettes_invalides");
+        assert!(normaliser_etiquettes(&vec![String::from("")]).is_err());
+        assert!(normaliser_etiquettes(&vec![String::from("   ")]).is_err());
+        assert!(normaliser_etiquettes(&vec!["e".repeat(TAILLE_MAXIMUM_ETIQUETTE + 1)]).is_err());
+        // La taille est comptee en caracteres
+        assert!(normaliser_etiquettes(&vec!["é".repeat(TAILLE_MAXIMUM_ETIQUETTE)]).is_ok());
+
+        let trop: Vec<String> = (0..=NOMBRE_MAXIMUM_ETIQUETTES).map(|i| format!("e{}", i)).collect();
+        assert!(normaliser_etiquettes(&trop).is_err());
+        let maximum: Vec<String> = (0..NOMBRE_MAXIMUM_ETIQUETTES).map(|i| format!("e{}", i)).collect();
+        assert_eq!(NOMBRE_MAXIMUM_ETIQUETTES, normaliser_etiquettes(&maximum).unwrap().len());
+    }
+
+    fn enveloppe_fichiers(nombre_fichiers: Option<i32>) -> EnveloppeMessage {
+        EnveloppeMessage { taille: 10, champs: vec![], nombre_fichiers }
+    }
+
+    fn champs_fichier() -> Vec<ChampFormulaire> {
+        from_value(json!([{"nom_champ": "Piece jointe", "code_interne": "piece", "type_champ": "fichier"}]))
+            .expect("champs")
+    }
+
+    fn fichier(fuuid: &str) -> FichierMessage {
+        FichierMessage {
+            fuuid: fuuid.to_owned(), taille: 100, mimetype: String::from("application/pdf"),
+            code_interne: Some(String::from("piece")), header: None,
+        }
+    }
+
+    #[test]
+    fn test_fichiers_declares_sans_fichiers() {
+        setup("test_fichiers_declares_sans_fichiers");
+        let limites = LimitesFichiers::default();
+        let champs = champs_fichier();
+
+        // nombre_fichiers declare mais aucune liste de fichiers
+        let enveloppe = enveloppe_fichiers(Some(2));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &[]).is_err());
+
+        // Au-dela de la limite de l'application
+        let enveloppe = enveloppe_fichiers(Some(limites.nombre_maximum as i32 + 1));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &[]).is_err());
+
+        let enveloppe = enveloppe_fichiers(Some(-1));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &[]).is_err());
+
+        let enveloppe = enveloppe_fichiers(Some(0));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &[]).is_ok());
+        let enveloppe = enveloppe_fichiers(None);
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &[]).is_ok());
+    }
+
+    #[test]
+    fn test_fichiers_nombre_declare() {
+        setup("test_fichiers_nombre_declare");
+        let limites = LimitesFichiers::default();
+        let champs = champs_fichier();
+        let fichiers = vec![fichier("zFICHIER1"), fichier("zFICHIER2")];
+
+        let enveloppe = enveloppe_fichiers(Some(2));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &fichiers).is_ok());
+        let enveloppe = enveloppe_fichiers(Some(1));
+        assert!(valider_fichiers_message(&limites, Some(&champs), Some(&enveloppe), &fichiers).is_err());
+
+        let trop: Vec<FichierMessage> = (0..=limites.nombre_maximum).map(|i| fichier(format!("zFICHIER{}", i).as_str())).collect();
+        assert!(valider_fichiers_message(&limites, Some(&champs), None, &trop).is_err());
+    }
+
+    fn champs_courriel_nombre() -> Vec<ChampFormulaire> {
+        from_value(json!([
+            {"nom_champ": "Courriel", "code_interne": "courriel", "type_champ": "courriel"},
+            {"nom_champ": "Age", "code_interne": "age", "type_champ": "nombre"},
+        ])).expect("champs")
+    }
+
+    fn enveloppe_valeur(code_interne: &str, valeur: &str) -> EnveloppeMessage {
+        let champ = EnveloppeChamp {
+            code_interne: code_interne.to_owned(), taille: valeur.len() as i64, valeur: Some(valeur.to_owned()),
+        };
+        EnveloppeMessage { taille: 1000, champs: vec![champ], nombre_fichiers: None }
+    }
+
+    #[test]
+    fn test_champs_courriel_nombre_formulaire() {
+        setup("test_champs_courriel_nombre_formulaire");
+        assert!(valider_champs_formulaire(&champs_courriel_nombre()).is_ok());
+
+        let champs: Vec<ChampFormulaire> = from_value(json!([
+            {"nom_champ": "Courriel", "code_interne": "courriel", "type_champ": "courriel", "taille_maximum": 1000},
+        ])).unwrap();
+        assert!(valider_champs_formulaire(&champs).is_err());
+
+        let champs: Vec<ChampFormulaire> = from_value(json!([
+            {"nom_champ": "Age", "code_interne": "age", "type_champ": "nombre", "choix": ["1", "2"]},
+        ])).unwrap();
+        assert!(valider_champs_formulaire(&champs).is_err());
+    }
+
+    #[test]
+    fn test_enveloppe_courriel_nombre() {
+        setup("test_enveloppe_courriel_nombre");
+        let champs = champs_courriel_nombre();
+
+        assert!(valider_enveloppe_message(&champs, &enveloppe_valeur("courriel", "nom@domaine.com")).is_ok());
+        for invalide in ["nom", "nom@", "@domaine.com", "nom@domaine", "a@b@domaine.com", "nom @domaine.com"] {
+            assert!(valider_enveloppe_message(&champs, &enveloppe_valeur("courriel", invalide)).is_err(), "{}", invalide);
+        }
+
+        assert!(valider_enveloppe_message(&champs, &enveloppe_valeur("age", "42")).is_ok());
+        assert!(valider_enveloppe_message(&champs, &enveloppe_valeur("age", "-1.5")).is_ok());
+        for invalide in ["abc", "", "NaN", "inf"] {
+            assert!(valider_enveloppe_message(&champs, &enveloppe_valeur("age", invalide)).is_err(), "{}", invalide);
+        }
+
+        // Valeur chiffree seulement : la taille d'un courriel est verifiee
+        let champ = EnveloppeChamp { code_interne: String::from("courriel"), taille: 300, valeur: None };
+        let enveloppe = EnveloppeMessage { taille: 1000, champs: vec![champ], nombre_fichiers: None };
+        assert!(valider_enveloppe_message(&champs, &enveloppe).is_err());
+    }
+
+    #[test]
+    fn test_hachage_source() {
+        setup("test_hachage_source");
+        let adresse_1 = String::from("203.0.113.1");
+        let adresse_2 = String::from("203.0.113.2");
+
+        // Relais : un hachage par visiteur
+        let relais_1 = calculer_hachage_source("zRELAIS", true, Some(&adresse_1));
+        let relais_2 = calculer_hachage_source("zRELAIS", true, Some(&adresse_2));
+        assert_ne!(relais_1, relais_2);
+        assert_eq!(relais_1, calculer_hachage_source("zAUTRE_RELAIS", true, Some(&adresse_1)));
+        assert_eq!(64, relais_1.len());
+
+        // Visiteur : l'adresse fournie est ignoree
+        let visiteur = calculer_hachage_source("zVISITEUR", false, None);
+        assert_eq!(visiteur, calculer_hachage_source("zVISITEUR", false, Some(&adresse_1)));
+        assert_ne!(visiteur, calculer_hachage_source("zAUTRE_VISITEUR", false, None));
+        assert_ne!(visiteur, relais_1);
+    }
+}
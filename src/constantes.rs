@@ -1,6 +1,15 @@
 pub const DOMAINE_NOM: &str = "Landing";
 pub const NOM_COLLECTION_TRANSACTIONS: &str = DOMAINE_NOM;
 pub const NOM_COLLECTION_APPLICATIONS: &str = "Landing/applications";
+pub const NOM_COLLECTION_MESSAGES: &str = "Landing/messages";
+pub const NOM_COLLECTION_VERSIONS: &str = "Landing/versions";
+pub const NOM_COLLECTION_COMPTEURS: &str = "Landing/compteurs";
+pub const NOM_COLLECTION_STATISTIQUES: &str = "Landing/statistiques";
+pub const NOM_COLLECTION_DEFIS: &str = "Landing/defis";
+pub const NOM_COLLECTION_JETONS: &str = "Landing/jetons";
+pub const NOM_COLLECTION_BLOCAGES: &str = "Landing/blocages";
+pub const NOM_COLLECTION_FICHIERS: &str = "Landing/fichiers";
+pub const NOM_COLLECTION_TRANSFERTS: &str = "Landing/transferts";
 
 pub const NOM_Q_TRANSACTIONS: &str = "Landing/transactions";
 pub const NOM_Q_VOLATILS: &str = "Landing/volatils";
@@ -8,11 +17,200 @@ pub const NOM_Q_TRIGGERS: &str = "Landing/triggers";
 
 pub const TRANSACTION_CREER_NOUVELLE_APPLICATION: &str = "creerNouvelleApplication";
 pub const TRANSACTION_SAUVEGARDER_APPLICATION: &str = "sauvegarderApplication";
+pub const TRANSACTION_SUPPRIMER_APPLICATION: &str = "supprimerApplication";
+pub const TRANSACTION_ARCHIVER_APPLICATION: &str = "archiverApplication";
+pub const TRANSACTION_RESTAURER_APPLICATION: &str = "restaurerApplication";
+pub const TRANSACTION_SAUVEGARDER_PAGE_APPLICATION: &str = "sauvegarderPageApplication";
+pub const TRANSACTION_PUBLIER_APPLICATION: &str = "publierApplication";
+pub const TRANSACTION_RESTAURER_VERSION_APPLICATION: &str = "restaurerVersionApplication";
+pub const TRANSACTION_SAUVEGARDER_HORAIRE_APPLICATION: &str = "sauvegarderHoraireApplication";
+pub const TRANSACTION_APPLIQUER_HORAIRE_APPLICATION: &str = "appliquerHoraireApplication";
+pub const TRANSACTION_PURGER_APPLICATIONS: &str = "purgerApplications";
+pub const TRANSACTION_SUPPRIMER_APPLICATIONS_USAGER: &str = "supprimerApplicationsUsager";
+pub const TRANSACTION_SAUVEGARDER_LIMITE_SOUMISSIONS: &str = "sauvegarderLimiteSoumissions";
+pub const TRANSACTION_SAUVEGARDER_LIMITE_DEBIT: &str = "sauvegarderLimiteDebit";
+pub const TRANSACTION_SAUVEGARDER_DIFFICULTE_DEFI: &str = "sauvegarderDifficulteDefi";
+pub const TRANSACTION_SAUVEGARDER_CONFIGURATION_SPAM: &str = "sauvegarderConfigurationSpam";
+pub const TRANSACTION_LIBERER_MESSAGES: &str = "libererMessages";
+pub const TRANSACTION_SUPPRIMER_MESSAGES: &str = "supprimerMessages";
+pub const TRANSACTION_CHANGER_ETAT_MESSAGES: &str = "changerEtatMessages";
+pub const TRANSACTION_ETIQUETER_MESSAGES: &str = "etiqueterMessages";
+pub const TRANSACTION_ASSIGNER_MESSAGES: &str = "assignerMessages";
+pub const TRANSACTION_BLOQUER_SOURCE: &str = "bloquerSource";
+pub const TRANSACTION_DEBLOQUER_SOURCE: &str = "debloquerSource";
+pub const TRANSACTION_SAUVEGARDER_LIMITES_FICHIERS: &str = "sauvegarderLimitesFichiers";
+pub const TRANSACTION_SAUVEGARDER_TRANSFERT_MESSAGERIE: &str = "sauvegarderTransfertMessagerie";
+pub const TRANSACTION_SOUMETTRE_MESSAGE: &str = "soumettreMessage";
 // pub const TRANSACTION_SAUVEGARDER_GROUPE_USAGER: &str = "sauvegarderGroupeUsager";
 // pub const TRANSACTION_SAUVEGARDER_DOCUMENT: &str = "sauvegarderDocument";
 
+pub const DOMAINE_FICHIERS_NOM: &str = "fichiers";
+pub const EVENEMENT_FICHIERS_CONSIGNE: &str = "consigne";
+pub const EVENEMENT_CONFIRMER_ETAT_FUUIDS: &str = "confirmerEtatFuuids";
+pub const EVENEMENT_SUPPRIMER_USAGER: &str = "supprimerUsager";
+pub const EVENEMENT_FUUIDS_LIBERES: &str = "fuuidsLiberes";
+
+/// Evenements emis pour les clients du proprietaire (partition user_id)
+pub const EVENEMENT_NOUVEAU_MESSAGE: &str = "nouveauMessage";
+pub const EVENEMENT_APPLICATION_MAJ: &str = "applicationMaj";
+
+/// Transfert des messages vers la boite de Messagerie du proprietaire
+pub const DOMAINE_MESSAGERIE_NOM: &str = "Messagerie";
+pub const COMMANDE_MESSAGERIE_RECEVOIR: &str = "recevoir";
+
 pub const REQUETE_LISTE_APPLICATIONS: &str = "getListeApplications";
 pub const REQUETE_APPLICATION: &str = "getApplication";
+pub const REQUETE_MESSAGES: &str = "getMessages";
+pub const REQUETE_MESSAGE: &str = "getMessage";
+pub const REQUETE_CLES_MESSAGES: &str = "getClesMessages";
+pub const REQUETE_APPLICATION_PUBLIQUE: &str = "getApplicationPublique";
+pub const REQUETE_VERSIONS_APPLICATION: &str = "getVersionsApplication";
+pub const REQUETE_ACTIONS: &str = "getActions";
+pub const REQUETE_STATISTIQUES_APPLICATION: &str = "getStatistiquesApplication";
+pub const REQUETE_DEFI_SOUMISSION: &str = "getDefiSoumission";
+pub const REQUETE_BLOCAGES: &str = "getBlocages";
+pub const REQUETE_FICHIERS_MANQUANTS: &str = "getFichiersManquants";
+
+/// Duree de conservation (jours) d'une application supprimee avant la purge.
+/// Peut etre modifiee avec la variable d'environnement ENV_DUREE_CORBEILLE.
+pub const DUREE_CORBEILLE_DEFAUT_JOURS: i64 = 30;
+pub const ENV_DUREE_CORBEILLE: &str = "MG_LANDING_CORBEILLE_JOURS";
+
+/// Taille maximale du contenu chiffre d'un message soumis (bytes)
+pub const TAILLE_MAXIMUM_MESSAGE: usize = 5 * 1024 * 1024;
+
+/// Limites (caracteres) du contenu de la page d'une application
+pub const TAILLE_MAXIMUM_PAGE_TITRE: usize = 200;
+pub const TAILLE_MAXIMUM_PAGE_CONTENU: usize = 64 * 1024;
+pub const TAILLE_MAXIMUM_PAGE_REMERCIEMENT: usize = 4 * 1024;
+pub const TAILLE_MAXIMUM_PAGE_URL: usize = 2048;
+pub const TAILLE_MAXIMUM_FUUID: usize = 128;
+
+/// Taille maximale (bytes) d'un champ de formulaire de type courriel (RFC 5321)
+pub const TAILLE_MAXIMUM_COURRIEL: i32 = 254;
+
+/// Limites de debit des soumissions publiques, format "nombre/secondes" pour les variables d'environnement.
+pub const ENV_DEBIT_GLOBAL: &str = "MG_LANDING_DEBIT_GLOBAL";
+pub const ENV_DEBIT_SOURCE: &str = "MG_LANDING_DEBIT_SOURCE";
+pub const DEBIT_GLOBAL_DEFAUT_NOMBRE: u32 = 600;
+pub const DEBIT_GLOBAL_DEFAUT_SECONDES: u32 = 60;
+pub const DEBIT_SOURCE_DEFAUT_NOMBRE: u32 = 5;
+pub const DEBIT_SOURCE_DEFAUT_SECONDES: u32 = 60;
+/// Limites de debit de l'emission des jetons de formulaire (getApplicationPublique)
+pub const ENV_DEBIT_JETONS_GLOBAL: &str = "MG_LANDING_DEBIT_JETONS_GLOBAL";
+pub const ENV_DEBIT_JETONS_SOURCE: &str = "MG_LANDING_DEBIT_JETONS_SOURCE";
+pub const DEBIT_JETONS_GLOBAL_DEFAUT_NOMBRE: u32 = 1200;
+pub const DEBIT_JETONS_GLOBAL_DEFAUT_SECONDES: u32 = 60;
+pub const DEBIT_JETONS_SOURCE_DEFAUT_NOMBRE: u32 = 30;
+pub const DEBIT_JETONS_SOURCE_DEFAUT_SECONDES: u32 = 60;
+pub const DEBIT_FENETRE_MAXIMUM_SECONDES: u32 = 24 * 3600;
+pub const DEBIT_REDIS_DELAI_RECONNEXION_SECONDES: i64 = 30;
+pub const PREFIXE_CLE_REDIS_DEBIT: &str = "landing:debit";
+pub const ENV_REDIS_URL: &str = "MG_REDIS_URL";
+pub const ENV_REDIS_PASSWORD_FILE: &str = "MG_REDIS_PASSWORD_FILE";
+
+/// Defi (preuve de travail) des soumissions anonymes. La difficulte est en bits a zero au debut
+/// du hachage sha256.
+pub const DEFI_DUREE_VALIDITE_SECONDES: i64 = 600;
+pub const DIFFICULTE_DEFI_MAXIMUM: u32 = 28;
+pub const TAILLE_MAXIMUM_SOLUTION_DEFI: usize = 64;
+
+/// Detection de spam : pointage par critere et seuil de mise en quarantaine par defaut
+pub const POINTAGE_SPAM_PIEGE: i32 = 100;
+pub const POINTAGE_SPAM_DELAI: i32 = 50;
+pub const POINTAGE_SPAM_DOUBLON: i32 = 50;
+pub const POINTAGE_SPAM_MOT_CLE: i32 = 25;
+pub const SEUIL_SPAM_DEFAUT: i32 = 50;
+pub const DELAI_MINIMUM_SPAM_MAXIMUM_SECONDES: i64 = 3600;
+pub const NOMBRE_MAXIMUM_MOTS_CLES_SPAM: usize = 200;
+pub const NOMBRE_MAXIMUM_CHAMPS_PIEGES: usize = 10;
+/// Periode de detection des soumissions en doublon
+pub const DUREE_DOUBLON_SPAM_SECONDES: i64 = 24 * 3600;
+/// Duree de validite d'un jeton de formulaire (getApplicationPublique)
+pub const JETON_DUREE_VALIDITE_SECONDES: i64 = 24 * 3600;
+/// Nombre maximal de messages par transaction liberer/supprimer
+pub const NOMBRE_MAXIMUM_MESSAGES_TRANSACTION: usize = 100;
+/// Etiquettes et assignation des messages (suivi des demandes)
+pub const NOMBRE_MAXIMUM_ETIQUETTES: usize = 20;
+pub const TAILLE_MAXIMUM_ETIQUETTE: usize = 64;
+pub const TAILLE_MAXIMUM_ASSIGNE: usize = 128;
+
+/// Limites par defaut des fichiers joints a un message (sauvegarderLimitesFichiers)
+pub const FICHIERS_NOMBRE_DEFAUT: u32 = 5;
+pub const FICHIERS_NOMBRE_MAXIMUM: u32 = 50;
+pub const FICHIERS_TAILLE_TOTALE_DEFAUT: i64 = 25 * 1024 * 1024;
+pub const FICHIERS_TAILLE_TOTALE_MAXIMUM: i64 = 2 * 1024 * 1024 * 1024;
+pub const TAILLE_MAXIMUM_MIMETYPE: usize = 128;
+/// Duree de conservation de l'etat d'un fichier consigne qui n'est reference par aucun message
+pub const DUREE_FICHIER_NON_REFERENCE_JOURS: i64 = 7;
+/// Minute (de chaque heure) de la collecte des fichiers orphelins (cedule)
+pub const MINUTE_COLLECTE_FICHIERS: u32 = 15;
+/// Delai avant qu'un fichier qui n'est plus reference soit libere
+pub const DELAI_LIBERATION_FICHIER_MINUTES: i64 = 60;
+pub const TAILLE_LOT_COLLECTE_FICHIERS: usize = 1000;
+
+/// Reprise des transferts vers Messagerie en echec : delai double a chaque tentative
+pub const TRANSFERT_DELAI_REPRISE_SECONDES: i64 = 60;
+pub const TRANSFERT_DELAI_REPRISE_MAXIMUM_SECONDES: i64 = 6 * 3600;
+pub const TRANSFERT_TENTATIVES_MAXIMUM: u32 = 12;
+pub const TAILLE_LOT_TRANSFERTS: i64 = 50;
+
+/// Taille maximale d'un identificateur de source bloquee (fingerprint, jeton client, hachage)
+pub const TAILLE_MAXIMUM_SOURCE: usize = 256;
+
+/// Raisons de rejet de soumissions (statistiques)
+pub const REJET_DEBIT: &str = "debit";
+pub const REJET_DEFI: &str = "defi";
+pub const REJET_JETON: &str = "jeton";
 
 pub const CHAMP_USER_ID: &str = "user_id";
 pub const CHAMP_APPLICATION_ID: &str = "application_id";
+pub const CHAMP_MESSAGE_ID: &str = "message_id";
+pub const CHAMP_ACTIF: &str = "actif";
+pub const CHAMP_SUPPRIME: &str = "supprime";
+pub const CHAMP_DATE_SUPPRESSION: &str = "date_suppression";
+pub const CHAMP_ARCHIVE: &str = "archive";
+pub const CHAMP_DATE_RECEPTION: &str = "date_reception";
+pub const CHAMP_LU: &str = "lu";
+pub const CHAMP_PAGE: &str = "page";
+pub const CHAMP_BROUILLON: &str = "brouillon";
+pub const CHAMP_VERSION: &str = "version";
+pub const CHAMP_VERSION_PUBLIEE: &str = "version_publiee";
+pub const CHAMP_VERSION_APPLICATION: &str = "version_application";
+pub const CHAMP_DATE_OUVERTURE: &str = "date_ouverture";
+pub const CHAMP_DATE_FERMETURE: &str = "date_fermeture";
+pub const CHAMP_HORAIRE: &str = "horaire";
+pub const CHAMP_OUVERT_HORAIRE: &str = "ouvert_horaire";
+pub const CHAMP_LIMITE_SOUMISSIONS: &str = "limite_soumissions";
+pub const CHAMP_PERIODE: &str = "periode";
+pub const CHAMP_NOMBRE: &str = "nombre";
+pub const CHAMP_LIMITE_DEBIT: &str = "limite_debit";
+pub const CHAMP_LIMITE_DEBIT_SOURCE: &str = "limite_debit_source";
+pub const CHAMP_REJETS: &str = "rejets";
+pub const CHAMP_DIFFICULTE_DEFI: &str = "difficulte_defi";
+pub const CHAMP_DEFI_ID: &str = "defi_id";
+pub const CHAMP_EXPIRATION: &str = "expiration";
+pub const CHAMP_UTILISE: &str = "utilise";
+pub const CHAMP_CONFIGURATION_SPAM: &str = "configuration_spam";
+pub const CHAMP_JETON_ID: &str = "jeton_id";
+pub const CHAMP_QUARANTAINE: &str = "quarantaine";
+pub const CHAMP_HACHAGE_CONTENU: &str = "hachage_contenu";
+pub const CHAMP_SOURCE: &str = "source";
+pub const CHAMP_HACHAGE_SOURCE: &str = "hachage_source";
+pub const CHAMP_CLE_MESSAGERIE: &str = "cle_messagerie";
+pub const CHAMP_FUUIDS: &str = "fuuids";
+pub const CHAMP_FUUIDS_CONSIGNES: &str = "fuuids_consignes";
+pub const CHAMP_FICHIERS_MANQUANTS: &str = "fichiers_manquants";
+pub const CHAMP_LIMITES_FICHIERS: &str = "limites_fichiers";
+pub const CHAMP_FUUID: &str = "fuuid";
+pub const CHAMP_ETAT: &str = "etat";
+pub const CHAMP_DATE_CONSIGNE: &str = "date_consigne";
+pub const CHAMP_REFERENCE: &str = "reference";
+pub const CHAMP_TRANSFERT_MESSAGERIE: &str = "transfert_messagerie";
+pub const CHAMP_DATE_ETAT: &str = "date_etat";
+pub const CHAMP_ETIQUETTES: &str = "etiquettes";
+pub const CHAMP_ASSIGNE: &str = "assigne";
+pub const CHAMP_TENTATIVES: &str = "tentatives";
+pub const CHAMP_PROCHAINE_TENTATIVE: &str = "prochaine_tentative";
+pub const CHAMP_DATE_TRANSFERT: &str = "date_transfert";
+pub const CHAMP_ERREUR: &str = "erreur";
@@ -125,11 +125,15 @@ async fn entretien<M>(gestionnaires: Vec<&'static TypeGestionnaire>, middleware:
         debug!("domaine.entretien  Execution task d'entretien Core {:?}", maintenant);
 
         if prochain_chargement_certificats_maitredescles < maintenant {
-            match middleware.charger_certificats_chiffrage(middleware.as_ref()).await {
-                Ok(()) => {
-                    prochain_chargement_certificats_maitredescles = maintenant + intervalle_chargement_certificats_maitredescles;
-                },
-                Err(e) => info!("Erreur chargement certificats de maitre des cles tiers : {:?}", e)
+            for g in &gestionnaires {
+                if let TypeGestionnaire::Landing(g) = g {
+                    match g.charger_certificats_chiffrage(middleware.as_ref()).await {
+                        Ok(()) => {
+                            prochain_chargement_certificats_maitredescles = maintenant + intervalle_chargement_certificats_maitredescles;
+                        },
+                        Err(e) => info!("Erreur chargement certificats de maitre des cles tiers : {:?}", e)
+                    }
+                }
             }
         }
 
@@ -1,41 +1,180 @@
 use std::error::Error;
 use log::debug;
-use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::constantes::Securite;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
-use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json::json;
+
+use crate::common::{trouver_fuuids_references, TransactionSupprimerApplicationsUsager};
+use crate::registre::{autoriser_action, Handler, HandlerEvenement, TypeAction};
+use crate::constantes::*;
 use crate::gestionnaire::GestionnaireLanding;
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementNouveauMessage<'a> {
+    application_id: &'a str,
+    message_id: &'a str,
+    /// Nombre de messages non lus de l'application (excluant la quarantaine)
+    non_lus: u64,
+}
+
+/// Emet evenement.Landing.{user_id}.nouveauMessage pour les clients du proprietaire.
+pub async fn emettre_evenement_nouveau_message<M>(middleware: &M, user_id: &str, application_id: &str, message_id: &str)
+    -> Result<(), String>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_APPLICATION_ID: application_id,
+        CHAMP_LU: false,
+        CHAMP_QUARANTAINE: {"$ne": true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let non_lus = match collection.count_documents(filtre, None).await {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("evenements.emettre_evenement_nouveau_message Erreur comptage non lus : {:?}", e))?
+    };
+
+    let evenement = EvenementNouveauMessage { application_id, message_id, non_lus };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_NOUVEAU_MESSAGE)
+        .partition(user_id)
+        .exchanges(vec![Securite::L2Prive])
+        .build();
+    match middleware.emettre_evenement(routage, &evenement).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("evenements.emettre_evenement_nouveau_message Erreur emission : {:?}", e))
+    }
+}
+
+/// Emet evenement.Landing.{user_id}.applicationMaj pour que les autres appareils du proprietaire
+/// rechargent l'application.
+pub async fn emettre_evenement_application_maj<M>(middleware: &M, user_id: &str, application_id: &str)
+    -> Result<(), String>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_APPLICATION_MAJ)
+        .partition(user_id)
+        .exchanges(vec![Securite::L2Prive])
+        .build();
+    match middleware.emettre_evenement(routage, &json!({"application_id": application_id})).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("evenements.emettre_evenement_application_maj Erreur emission : {:?}", e))
+    }
+}
+
 pub async fn consommer_evenement<M>(gestionnaire: &GestionnaireLanding, middleware: &M, m: MessageValideAction)
                                     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: ValidateurX509 + GenerateurMessages + MongoDao
 {
     debug!("gestionnaire.consommer_evenement Consommer evenement : {:?}", &m.message);
 
-    todo!("Fix me")
-
-    // // Autorisation selon l'action
-    // let niveau_securite_requis = match m.action.as_str() {
-    //     // EVENEMENT_UPLOAD_ATTACHMENT => Ok(Securite::L1Public),
-    //     EVENEMENT_POMPE_POSTE => Ok(Securite::L4Secure),
-    //     EVENEMENT_FICHIERS_CONSIGNE => Ok(Securite::L2Prive),
-    //     EVENEMENT_CONFIRMER_ETAT_FUUIDS => Ok(Securite::L2Prive),
-    //     _ => Err(format!("gestionnaire.consommer_evenement: Action inconnue : {}", m.action.as_str())),
-    // }?;
-    //
-    // if m.verifier_exchanges(vec![niveau_securite_requis.clone()]) {
-    //     match m.action.as_str() {
-    //         // EVENEMENT_UPLOAD_ATTACHMENT => evenement_upload_attachment(middleware, m).await,
-    //         EVENEMENT_POMPE_POSTE => evenement_pompe_poste(gestionnaire, middleware, &m).await,
-    //         EVENEMENT_FICHIERS_CONSIGNE => evenement_fichier_consigne(gestionnaire, middleware, &m).await,
-    //         EVENEMENT_CONFIRMER_ETAT_FUUIDS => evenement_confirmer_etat_fuuids(middleware, m).await,
-    //         _ => Err(format!("gestionnaire.consommer_transaction: Mauvais type d'action pour un evenement 1.public : {}", m.action))?,
-    //     }
-    // } else {
-    //     Err(format!("gestionnaire.consommer_evenement: Niveau de securite invalide pour action {} : doit etre {:?}",
-    //                 m.action.as_str(), niveau_securite_requis))?
-    // }
+    // Autorisation selon l'action
+    let handler = match autoriser_action(&m, TypeAction::Evenement)?.handler {
+        Handler::Evenement(h) => h,
+        _ => Err(format!("gestionnaire.consommer_evenement: Mauvais type d'action pour un evenement : {}", m.action))?,
+    };
+
+    match handler {
+        HandlerEvenement::FichiersConsigne => evenement_fichier_consigne(gestionnaire, middleware, &m).await,
+        HandlerEvenement::ConfirmerEtatFuuids => evenement_confirmer_etat_fuuids(middleware, m).await,
+        HandlerEvenement::SupprimerUsager => evenement_supprimer_usager(middleware, m).await,
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementFichierConsigne {
+    hachage_bytes: String,
+}
+
+async fn evenement_fichier_consigne<M>(_gestionnaire: &GestionnaireLanding, middleware: &M, m: &MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("evenement_fichier_consigne Traiter evenement fichiers : {:?}", m.message);
+    let evenement: EvenementFichierConsigne = m.message.get_msg().map_contenu(None)?;
+    let fuuid = evenement.hachage_bytes.as_str();
+
+    // Conserver l'etat du fichier, l'evenement peut arriver avant la transaction du message
+    let filtre = doc! { CHAMP_FUUID: fuuid };
+    let ops = doc! {
+        "$set": { CHAMP_ETAT: "consigne", CHAMP_DATE_CONSIGNE: Utc::now().timestamp() },
+        "$setOnInsert": { CHAMP_FUUID: fuuid, CHAMP_CREATION: Utc::now() },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection_fichiers = middleware.get_collection(NOM_COLLECTION_FICHIERS)?;
+    collection_fichiers.update_one(filtre, ops, options).await?;
+
+    // Marquer le fichier comme consigne pour tous les messages qui y font reference
+    let filtre = doc! { CHAMP_FUUIDS: fuuid };
+    let ops = doc! {
+        "$addToSet": { CHAMP_FUUIDS_CONSIGNES: fuuid },
+        "$pull": { CHAMP_FICHIERS_MANQUANTS: fuuid },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let resultat = collection.update_many(filtre, ops, None).await?;
+    debug!("evenement_fichier_consigne Fuuid {} consigne pour {} messages", fuuid, resultat.modified_count);
+
+    Ok(None)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementConfirmerEtatFuuids {
+    fuuids: Vec<String>,
+}
+
+async fn evenement_confirmer_etat_fuuids<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("evenement_confirmer_etat_fuuids Traiter evenement : {:?}", m.message);
+    let evenement: EvenementConfirmerEtatFuuids = m.message.get_msg().map_contenu(None)?;
+
+    // Trouver les fuuids encore references (messages, pages des applications et versions)
+    let fuuids_references = trouver_fuuids_references(middleware, &evenement.fuuids).await?;
+
+    let etat_fuuids: Vec<_> = evenement.fuuids.iter()
+        .map(|f| json!({"fuuid": f, "supprime": ! fuuids_references.contains(f)}))
+        .collect();
+
+    let reponse = json!({"fuuids": etat_fuuids});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementSupprimerUsager {
+    user_id: String,
+}
+
+async fn evenement_supprimer_usager<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("evenement_supprimer_usager Traiter evenement : {:?}", m.message);
+    let evenement: EvenementSupprimerUsager = m.message.get_msg().map_contenu(None)?;
+
+    // Mettre les applications de l'usager a la corbeille avec une transaction du domaine pour que
+    // la suppression soit conservee lors d'une regeneration.
+    let filtre = doc! { CHAMP_USER_ID: &evenement.user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    if collection.count_documents(filtre, None).await? == 0 {
+        debug!("evenement_supprimer_usager Aucune application pour usager {}", evenement.user_id);
+        return Ok(None)
+    }
+
+    let transaction = TransactionSupprimerApplicationsUsager { user_id: evenement.user_id };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_SUPPRIMER_APPLICATIONS_USAGER)
+        .exchanges(vec![Securite::L4Secure])
+        .build();
+    middleware.soumettre_transaction(routage, &transaction, false).await?;
 
+    Ok(None)
 }
@@ -1,36 +1,101 @@
 use std::error::Error;
-use std::sync::Arc;
-use log::debug;
+use std::sync::{Arc, Mutex};
+use log::{debug, error, info, warn};
 use millegrilles_common_rust::async_trait::async_trait;
+use millegrilles_common_rust::bson::doc;
 use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::Timelike;
 use millegrilles_common_rust::configuration::ConfigMessages;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::domaines::GestionnaireDomaine;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
 use millegrilles_common_rust::futures::stream::FuturesUnordered;
-use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::messages_generiques::MessageCedule;
 use millegrilles_common_rust::middleware::Middleware;
-use millegrilles_common_rust::mongo_dao::{ChampIndex, IndexOptions, MongoDao};
+use millegrilles_common_rust::mongo_dao::{ChampIndex, convertir_bson_deserializable, IndexOptions, MongoDao};
 use millegrilles_common_rust::rabbitmq_dao::{ConfigQueue, ConfigRoutingExchange, QueueType};
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
 use millegrilles_common_rust::tokio::time::sleep;
 use millegrilles_common_rust::tokio::task::JoinHandle;
+use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::transactions::{TraiterTransaction, Transaction, TransactionImpl};
 
+use crate::common::*;
 use crate::constantes::*;
 use crate::commandes::consommer_commande;
 use crate::evenements::consommer_evenement;
+use crate::limiteur::LimiteurDebit;
+use crate::messagerie::transferer_messages;
+use crate::registre::{REGISTRE_ACTIONS, TypeAction};
 use crate::requetes::consommer_requete;
 use crate::transactions::{aiguillage_transaction, consommer_transaction};
 
 #[derive(Clone, Debug)]
-pub struct GestionnaireLanding {}
+pub struct GestionnaireLanding {
+    /// Duree de conservation d'une application dans la corbeille avant la purge
+    pub duree_corbeille: chrono::Duration,
+    /// Limiteur de debit des soumissions publiques
+    pub limiteur_debit: Arc<LimiteurDebit>,
+    /// Certificats (chaine PEM) de maitre des cles charges par charger_certificats_chiffrage
+    certificats_chiffrage: Arc<Mutex<Vec<Vec<String>>>>,
+}
 
 impl GestionnaireLanding {
 
     pub fn new() -> Self {
-        return Self {}
+        let jours_corbeille = match std::env::var(ENV_DUREE_CORBEILLE) {
+            Ok(v) => match v.parse::<i64>() {
+                Ok(j) if j >= 0 => j,
+                Ok(j) => {
+                    warn!("Valeur {} invalide ({}), utiliser defaut {} jours", ENV_DUREE_CORBEILLE, j, DUREE_CORBEILLE_DEFAUT_JOURS);
+                    DUREE_CORBEILLE_DEFAUT_JOURS
+                },
+                Err(e) => {
+                    warn!("Valeur {} invalide ({:?}), utiliser defaut {} jours", ENV_DUREE_CORBEILLE, e, DUREE_CORBEILLE_DEFAUT_JOURS);
+                    DUREE_CORBEILLE_DEFAUT_JOURS
+                }
+            },
+            Err(_) => DUREE_CORBEILLE_DEFAUT_JOURS
+        };
+        return Self {
+            duree_corbeille: chrono::Duration::days(jours_corbeille),
+            limiteur_debit: Arc::new(LimiteurDebit::new()),
+            certificats_chiffrage: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Charge les certificats de maitre des cles (charger_certificats_chiffrage du middleware) et
+    /// conserve leur chaine PEM pour le formulaire public. Le certificat de millegrille est exclu, le
+    /// client le connait deja.
+    pub async fn charger_certificats_chiffrage<M>(&self, middleware: &M) -> Result<(), Box<dyn Error>>
+        where M: Middleware
+    {
+        if let Err(e) = middleware.charger_certificats_chiffrage(middleware).await {
+            Err(format!("gestionnaire.charger_certificats_chiffrage Erreur chargement : {:?}", e))?
+        }
+
+        let mut certificats = Vec::new();
+        for cle in middleware.get_publickeys_chiffrage() {
+            if cle.est_cle_millegrille {
+                continue
+            }
+            if let Some(certificat) = middleware.get_certificat(cle.fingerprint.as_str()).await {
+                let pems: Vec<String> = certificat.get_pem_vec().into_iter().map(|c| c.pem).collect();
+                certificats.push(pems);
+            }
+        }
+
+        let mut guard = self.certificats_chiffrage.lock().expect("lock certificats_chiffrage");
+        *guard = certificats;
+        Ok(())
+    }
+
+    /// Certificats de maitre des cles conserves par le dernier charger_certificats_chiffrage.
+    pub fn get_certificats_chiffrage(&self) -> Vec<Vec<String>> {
+        self.certificats_chiffrage.lock().expect("lock certificats_chiffrage").clone()
     }
 
 }
@@ -53,6 +118,10 @@ impl GestionnaireDomaine for GestionnaireLanding {
     fn get_collections_documents(&self) -> Vec<String> {
         vec![
             String::from(NOM_COLLECTION_APPLICATIONS),
+            String::from(NOM_COLLECTION_MESSAGES),
+            String::from(NOM_COLLECTION_VERSIONS),
+            String::from(NOM_COLLECTION_COMPTEURS),
+            String::from(NOM_COLLECTION_BLOCAGES),
         ]
     }
 
@@ -123,23 +192,18 @@ impl GestionnaireDomaine for GestionnaireLanding {
 
 pub fn preparer_queues() -> Vec<QueueType> {
     let mut rk_volatils = Vec::new();
+    let mut rk_transactions = Vec::new();
 
-    // RK 2.prive
-    let requetes_privees: Vec<&str> = vec![
-        REQUETE_LISTE_APPLICATIONS,
-        REQUETE_APPLICATION,
-    ];
-    for req in requetes_privees {
-        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L2Prive});
-    }
-
-    let commandes_privees: Vec<&str> = vec![
-        // Transactions
-        TRANSACTION_CREER_NOUVELLE_APPLICATION,
-        TRANSACTION_SAUVEGARDER_APPLICATION,
-    ];
-    for cmd in commandes_privees {
-        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L2Prive});
+    // Routing keys de toutes les actions du registre
+    for definition in REGISTRE_ACTIONS {
+        let routing_key = definition.routing_key();
+        for exchange in definition.exchanges {
+            let rk = ConfigRoutingExchange {routing_key: routing_key.clone(), exchange: exchange.clone()};
+            match definition.type_action() {
+                TypeAction::Transaction => rk_transactions.push(rk),
+                _ => rk_volatils.push(rk),
+            }
+        }
     }
 
     let mut queues = Vec::new();
@@ -155,18 +219,6 @@ pub fn preparer_queues() -> Vec<QueueType> {
         }
     ));
 
-    let mut rk_transactions = Vec::new();
-    let transactions_secures: Vec<&str> = vec![
-        TRANSACTION_CREER_NOUVELLE_APPLICATION,
-        TRANSACTION_SAUVEGARDER_APPLICATION,
-    ];
-    for ts in transactions_secures {
-        rk_transactions.push(ConfigRoutingExchange {
-            routing_key: format!("transaction.{}.{}", DOMAINE_NOM, ts).into(),
-            exchange: Securite::L4Secure
-        });
-    }
-
     // Queue de transactions
     queues.push(QueueType::ExchangeQueue (
         ConfigQueue {
@@ -203,12 +255,203 @@ pub async fn preparer_index_mongodb_custom<M>(middleware: &M) -> Result<(), Stri
         Some(options_unique_applications)
     ).await?;
 
+    // Index message_id pour messages
+    let options_unique_messages = IndexOptions {
+        nom_index: Some(String::from("messages")),
+        unique: true
+    };
+    let champs_index_messages = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_MESSAGE_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_MESSAGES,
+        champs_index_messages,
+        Some(options_unique_messages)
+    ).await?;
+
+    // Index application_id / version pour versions
+    let options_unique_versions = IndexOptions {
+        nom_index: Some(String::from("versions")),
+        unique: true
+    };
+    let champs_index_versions = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_APPLICATION_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_VERSION), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_VERSIONS,
+        champs_index_versions,
+        Some(options_unique_versions)
+    ).await?;
+
+    // Index application_id / periode pour compteurs
+    let options_unique_compteurs = IndexOptions {
+        nom_index: Some(String::from("compteurs")),
+        unique: true
+    };
+    let champs_index_compteurs = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_APPLICATION_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_PERIODE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_COMPTEURS,
+        champs_index_compteurs,
+        Some(options_unique_compteurs)
+    ).await?;
+
+    // Index defi_id pour defis
+    let options_unique_defis = IndexOptions {
+        nom_index: Some(String::from("defis")),
+        unique: true
+    };
+    let champs_index_defis = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_DEFI_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_DEFIS,
+        champs_index_defis,
+        Some(options_unique_defis)
+    ).await?;
+
+    // Index jeton_id pour jetons
+    let options_unique_jetons = IndexOptions {
+        nom_index: Some(String::from("jetons")),
+        unique: true
+    };
+    let champs_index_jetons = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_JETON_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_JETONS,
+        champs_index_jetons,
+        Some(options_unique_jetons)
+    ).await?;
+
+    // Index source / application_id pour blocages
+    let options_unique_blocages = IndexOptions {
+        nom_index: Some(String::from("blocages")),
+        unique: true
+    };
+    let champs_index_blocages = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_SOURCE), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_APPLICATION_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_BLOCAGES,
+        champs_index_blocages,
+        Some(options_unique_blocages)
+    ).await?;
+
+    // Index fuuid pour fichiers
+    let options_unique_fichiers = IndexOptions {
+        nom_index: Some(String::from("fichiers")),
+        unique: true
+    };
+    let champs_index_fichiers = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_FUUID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_FICHIERS,
+        champs_index_fichiers,
+        Some(options_unique_fichiers)
+    ).await?;
+
+    // Index user_id / etat pour messages (suivi des demandes)
+    let options_messages_etat = IndexOptions {
+        nom_index: Some(String::from("messages_etat")),
+        unique: false
+    };
+    let champs_index_messages_etat = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_ETAT), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_DATE_RECEPTION), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_MESSAGES,
+        champs_index_messages_etat,
+        Some(options_messages_etat)
+    ).await?;
+
+    // Index message_id pour transferts
+    let options_unique_transferts = IndexOptions {
+        nom_index: Some(String::from("transferts")),
+        unique: true
+    };
+    let champs_index_transferts = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_MESSAGE_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TRANSFERTS,
+        champs_index_transferts,
+        Some(options_unique_transferts)
+    ).await?;
+
+    // Index etat / prochaine_tentative pour transferts (reprise)
+    let options_transferts_attente = IndexOptions {
+        nom_index: Some(String::from("transferts_attente")),
+        unique: false
+    };
+    let champs_index_transferts_attente = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_ETAT), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_PROCHAINE_TENTATIVE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TRANSFERTS,
+        champs_index_transferts_attente,
+        Some(options_transferts_attente)
+    ).await?;
+
+    // Index fuuids pour messages (evenements de consignation)
+    let options_messages_fuuids = IndexOptions {
+        nom_index: Some(String::from("messages_fuuids")),
+        unique: false
+    };
+    let champs_index_messages_fuuids = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_FUUIDS), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_MESSAGES,
+        champs_index_messages_fuuids,
+        Some(options_messages_fuuids)
+    ).await?;
+
+    // Index user_id / application_id pour messages
+    let options_messages_usager = IndexOptions {
+        nom_index: Some(String::from("messages_usager")),
+        unique: false
+    };
+    let champs_index_messages_usager = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_APPLICATION_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_DATE_RECEPTION), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_MESSAGES,
+        champs_index_messages_usager,
+        Some(options_messages_usager)
+    ).await?;
+
     Ok(())
 }
 
-pub async fn entretien<M>(_gestionnaire: &GestionnaireLanding, middleware: Arc<M>)
+pub async fn entretien<M>(gestionnaire: &GestionnaireLanding, middleware: Arc<M>)
     where M: Middleware + 'static
 {
+    let mut prochaine_purge_corbeille = chrono::Utc::now();
+    let intervalle_purge_corbeille = chrono::Duration::minutes(60);
+
     loop {
         sleep(core::time::Duration::new(30, 0)).await;
         if middleware.get_mode_regeneration() == true {
@@ -217,7 +460,68 @@ pub async fn entretien<M>(_gestionnaire: &GestionnaireLanding, middleware: Arc<M
         }
 
         debug!("Cycle entretien {}", DOMAINE_NOM);
+        let maintenant = chrono::Utc::now();
+
+        if let Err(e) = transferer_messages(middleware.as_ref()).await {
+            error!("Erreur transfert messages {} vers {} : {:?}", DOMAINE_NOM, DOMAINE_MESSAGERIE_NOM, e);
+        }
+
+        if prochaine_purge_corbeille < maintenant {
+            match purger_corbeille(gestionnaire, middleware.as_ref()).await {
+                Ok(()) => prochaine_purge_corbeille = maintenant + intervalle_purge_corbeille,
+                Err(e) => error!("Erreur purge corbeille {} : {:?}", DOMAINE_NOM, e)
+            }
+            gestionnaire.limiteur_debit.nettoyer();
+            if let Err(e) = purger_defis_expires(middleware.as_ref()).await {
+                error!("Erreur purge defis {} : {:?}", DOMAINE_NOM, e);
+            }
+        }
+    }
+}
+
+/// Emet la purge des applications (et leurs messages) qui sont dans la corbeille depuis plus
+/// longtemps que la duree de conservation. La suppression est faite par la transaction pour
+/// qu'une regeneration ne restaure pas les applications purgees.
+async fn purger_corbeille<M>(gestionnaire: &GestionnaireLanding, middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let date_limite = (chrono::Utc::now() - gestionnaire.duree_corbeille).timestamp();
+    let filtre = doc! { CHAMP_SUPPRIME: true, CHAMP_DATE_SUPPRESSION: {"$lt": date_limite} };
+
+    let collection_applications = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let mut application_ids = Vec::new();
+    let mut curseur = collection_applications.find(filtre, None).await?;
+    while let Some(d) = curseur.next().await {
+        let app: DocApplication = convertir_bson_deserializable(d?)?;
+        application_ids.push(app.application_id);
+    }
+
+    if application_ids.is_empty() {
+        return Ok(())
     }
+
+    info!("purger_corbeille Emettre purge de {} applications : {:?}", application_ids.len(), application_ids);
+    let transaction = TransactionPurgerApplications { application_ids };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_PURGER_APPLICATIONS)
+        .exchanges(vec![Securite::L4Secure])
+        .build();
+    middleware.soumettre_transaction(routage, &transaction, false).await?;
+
+    Ok(())
+}
+
+/// Supprime les defis de soumission et jetons de formulaire expires.
+async fn purger_defis_expires<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_EXPIRATION: {"$lt": chrono::Utc::now().timestamp()} };
+    let collection = middleware.get_collection(NOM_COLLECTION_DEFIS)?;
+    let resultat = collection.delete_many(filtre.clone(), None).await?;
+    debug!("purger_defis_expires {} defis supprimes", resultat.deleted_count);
+    let collection = middleware.get_collection(NOM_COLLECTION_JETONS)?;
+    let resultat = collection.delete_many(filtre, None).await?;
+    debug!("purger_defis_expires {} jetons supprimes", resultat.deleted_count);
+    Ok(())
 }
 
 pub async fn traiter_cedule<M>(gestionnaire: &GestionnaireLanding, middleware: &M, trigger: &MessageCedule)
@@ -231,11 +535,212 @@ pub async fn traiter_cedule<M>(gestionnaire: &GestionnaireLanding, middleware: &
         return Ok(())
     }
 
-    // let mut prochain_entretien_index_media = chrono::Utc::now();
-    // let intervalle_entretien_index_media = chrono::Duration::minutes(5);
-    //
-    // let date_epoch = trigger.get_date();
-    // let minutes = date_epoch.get_datetime().minute();
+    let date_epoch = trigger.get_date();
+    if let Err(e) = appliquer_horaires(middleware, date_epoch.get_datetime()).await {
+        error!("traiter_cedule Erreur application horaires : {:?}", e);
+    }
+
+    if date_epoch.get_datetime().minute() == MINUTE_COLLECTE_FICHIERS {
+        if let Err(e) = collecter_fichiers_orphelins(middleware).await {
+            error!("traiter_cedule Erreur collecte fichiers orphelins : {:?}", e);
+        }
+    }
 
     Ok(())
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementFuuidsLiberes {
+    fuuids: Vec<String>,
+}
+
+/// Libere les fichiers joints qui ne sont plus references par aucun message (messages supprimes ou
+/// purges). Un evenement est emis pour que le service de fichiers puisse les retirer.
+async fn collecter_fichiers_orphelins<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_FICHIERS)?;
+
+    // Retirer l'etat des fichiers consignes pour d'autres domaines (jamais references)
+    let date_limite = chrono::Utc::now() - chrono::Duration::days(DUREE_FICHIER_NON_REFERENCE_JOURS);
+    let filtre = doc! { CHAMP_REFERENCE: {"$ne": true}, CHAMP_CREATION: {"$lt": date_limite} };
+    let resultat = collection.delete_many(filtre, None).await?;
+    debug!("collecter_fichiers_orphelins {} fichiers non references retires", resultat.deleted_count);
+
+    // Delai pour eviter de liberer un fichier dont la transaction est en cours de traitement
+    let date_limite = chrono::Utc::now() - chrono::Duration::minutes(DELAI_LIBERATION_FICHIER_MINUTES);
+    let filtre = doc! { CHAMP_REFERENCE: true, CHAMP_MODIFICATION: {"$lt": date_limite} };
+    let mut curseur = collection.find(filtre, None).await?;
+
+    let mut nombre_liberes = 0;
+    let mut lot = Vec::new();
+    loop {
+        let fin = match curseur.next().await {
+            Some(d) => {
+                let fichier: DocFichier = convertir_bson_deserializable(d?)?;
+                lot.push(fichier.fuuid);
+                false
+            },
+            None => true
+        };
+
+        if lot.len() >= TAILLE_LOT_COLLECTE_FICHIERS || (fin && ! lot.is_empty()) {
+            let references = trouver_fuuids_references(middleware, &lot).await?;
+            let orphelins: Vec<String> = lot.drain(..).filter(|f| ! references.contains(f)).collect();
+            if ! orphelins.is_empty() {
+                nombre_liberes += orphelins.len();
+                liberer_fuuids(middleware, orphelins).await?;
+            }
+        }
+
+        if fin { break }
+    }
+
+    if nombre_liberes > 0 {
+        info!("collecter_fichiers_orphelins {} fichiers liberes", nombre_liberes);
+    }
+
+    Ok(())
+}
+
+/// Emet l'evenement de liberation des fuuids (service de fichiers) et retire leur etat.
+pub async fn liberer_fuuids<M>(middleware: &M, fuuids: Vec<String>) -> Result<(), String>
+    where M: GenerateurMessages + MongoDao
+{
+    let evenement = EvenementFuuidsLiberes { fuuids };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_FUUIDS_LIBERES)
+        .exchanges(vec![Securite::L2Prive])
+        .build();
+    if let Err(e) = middleware.emettre_evenement(routage, &evenement).await {
+        Err(format!("gestionnaire.liberer_fuuids Erreur emission evenement : {:?}", e))?
+    }
+
+    let filtre = doc! { CHAMP_FUUID: {"$in": &evenement.fuuids} };
+    let collection = middleware.get_collection(NOM_COLLECTION_FICHIERS)?;
+    if let Err(e) = collection.delete_many(filtre, None).await {
+        Err(format!("gestionnaire.liberer_fuuids Erreur retrait fichiers : {:?}", e))?
+    }
+
+    Ok(())
+}
+
+/// Ouvre ou ferme les applications dont l'etat attendu selon l'horaire a change depuis la derniere
+/// transition. Chaque transition est conservee sous forme de transaction.
+async fn appliquer_horaires<M>(middleware: &M, date: &chrono::DateTime<chrono::Utc>) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! {
+        CHAMP_SUPPRIME: {"$ne": true},
+        CHAMP_ARCHIVE: {"$ne": true},
+        "$or": [
+            {CHAMP_DATE_OUVERTURE: {"$ne": None::<i64>}},
+            {CHAMP_DATE_FERMETURE: {"$ne": None::<i64>}},
+            {CHAMP_HORAIRE: {"$ne": None::<i64>}},
+        ]
+    };
+
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    while let Some(d) = curseur.next().await {
+        let app: DocApplication = convertir_bson_deserializable(d?)?;
+        let ouvert = match app.est_ouvert_horaire(date) {
+            Some(inner) => inner,
+            None => continue
+        };
+        if app.ouvert_horaire == Some(ouvert) {
+            continue  // Aucune transition
+        }
+
+        debug!("appliquer_horaires Application {} ouvert : {}", app.application_id, ouvert);
+        let transaction = TransactionAppliquerHoraireApplication { application_id: app.application_id, actif: ouvert };
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_APPLIQUER_HORAIRE_APPLICATION)
+            .exchanges(vec![Securite::L4Secure])
+            .build();
+        middleware.soumettre_transaction(routage, &transaction, false).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_gestionnaire {
+    use super::*;
+    use crate::registre::get_definition_action;
+    use crate::test_setup::setup;
+
+    fn type_action_routing_key(prefixe: &str) -> TypeAction {
+        match prefixe {
+            "requete" => TypeAction::Requete,
+            "commande" => TypeAction::Commande,
+            "transaction" => TypeAction::Transaction,
+            "evenement" => TypeAction::Evenement,
+            _ => panic!("Prefixe de routing key inconnu : {}", prefixe)
+        }
+    }
+
+    /// Un certificat avec delegation globale est un certificat usager (proprietaire), emis sur 2.prive.
+    const EXCHANGES_DELEGATION: &[Securite] = &[Securite::L2Prive];
+
+    /// Exchanges sur lesquels les certificats d'un role emettent.
+    fn exchanges_role(role: &RolesCertificats) -> &'static [Securite] {
+        match role {
+            RolesCertificats::ComptePrive => &[Securite::L2Prive],
+            _ => panic!("Exchanges inconnus pour le role {:?}", role)
+        }
+    }
+
+    #[test]
+    fn test_routing_keys_autorisees() {
+        setup("test_routing_keys_autorisees");
+
+        let mut nombre_rk = 0;
+        for queue in preparer_queues() {
+            let config = match queue {
+                QueueType::ExchangeQueue(c) => c,
+                _ => continue
+            };
+            for rk in config.routing_keys {
+                nombre_rk += 1;
+                let parties: Vec<&str> = rk.routing_key.split(".").collect();
+                assert_eq!(3, parties.len(), "routing key {}", rk.routing_key);
+                let type_action = type_action_routing_key(parties[0]);
+
+                let definition = get_definition_action(type_action, parties[1], parties[2])
+                    .expect(format!("definition pour {}", rk.routing_key).as_str());
+
+                let autorisation = &definition.autorisation;
+                if ! autorisation.exchanges.is_empty() {
+                    // L'exchange de la Q doit etre accepte par la definition
+                    assert!(autorisation.exchanges.contains(&rk.exchange),
+                            "exchange {:?} refuse pour {}", rk.exchange, rk.routing_key);
+                } else {
+                    // Autorisation par role ou delegation seulement : l'exchange de la Q doit
+                    // transporter les messages de ces certificats
+                    assert!(! autorisation.roles.is_empty() || autorisation.delegation_globale,
+                            "aucune autorisation pour {}", rk.routing_key);
+                    for role in autorisation.roles {
+                        assert!(exchanges_role(role).contains(&rk.exchange),
+                                "exchange {:?} ne transporte pas le role {:?} pour {}", rk.exchange, role, rk.routing_key);
+                    }
+                    if autorisation.delegation_globale {
+                        assert!(EXCHANGES_DELEGATION.contains(&rk.exchange),
+                                "exchange {:?} ne transporte pas la delegation pour {}", rk.exchange, rk.routing_key);
+                    }
+                }
+            }
+        }
+
+        assert!(nombre_rk > 0);
+    }
+
+    #[test]
+    fn test_registre_sans_doublons() {
+        setup("test_registre_sans_doublons");
+        for (idx, definition) in REGISTRE_ACTIONS.iter().enumerate() {
+            let doublons = REGISTRE_ACTIONS[idx+1..].iter()
+                .filter(|d| d.routing_key() == definition.routing_key())
+                .count();
+            assert_eq!(0, doublons, "doublon pour {}", definition.routing_key());
+        }
+    }
+}
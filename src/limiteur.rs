@@ -0,0 +1,375 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::redis;
+use millegrilles_common_rust::redis::aio::MultiplexedConnection;
+use millegrilles_common_rust::redis::IntoConnectionInfo;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio::sync::Mutex as MutexAsync;
+
+use crate::constantes::*;
+
+/// Ajoute le membre a la fenetre (sorted set) seulement si la fenetre n'est pas pleine.
+/// KEYS[1] : cle, ARGV : maintenant (ms), fenetre (ms), maximum, membre.
+const SCRIPT_RESERVER_REDIS: &str = r"
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', tonumber(ARGV[1]) - tonumber(ARGV[2]))
+if redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('ZADD', KEYS[1], ARGV[1], ARGV[4])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return 1
+";
+
+/// Limite de debit : au plus `nombre` soumissions dans les `fenetre_secondes` dernieres secondes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LimiteDebit {
+    pub nombre: u32,
+    pub fenetre_secondes: u32,
+}
+
+impl LimiteDebit {
+    pub fn valider(&self) -> Result<(), String> {
+        if self.nombre < 1 { Err(format!("limite debit: nombre doit etre plus grand que 0"))? }
+        if self.fenetre_secondes < 1 || self.fenetre_secondes > DEBIT_FENETRE_MAXIMUM_SECONDES {
+            Err(format!("limite debit: fenetre_secondes invalide"))?
+        }
+        Ok(())
+    }
+
+    /// Format "nombre/secondes", e.g. "10/60".
+    fn parse(valeur: &str) -> Result<Self, String> {
+        let mut parties = valeur.split('/');
+        let nombre = parties.next().and_then(|v| v.trim().parse::<u32>().ok());
+        let fenetre_secondes = parties.next().and_then(|v| v.trim().parse::<u32>().ok());
+        match (nombre, fenetre_secondes, parties.next()) {
+            (Some(nombre), Some(fenetre_secondes), None) => {
+                let limite = Self { nombre, fenetre_secondes };
+                limite.valider()?;
+                Ok(limite)
+            },
+            _ => Err(format!("limiteur.LimiteDebit.parse Format invalide (nombre/secondes) : {}", valeur))
+        }
+    }
+
+    fn charger_env(nom_env: &str, defaut: LimiteDebit) -> Self {
+        match std::env::var(nom_env) {
+            Ok(v) => match Self::parse(v.as_str()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("Valeur {} invalide ({}), utiliser defaut {:?}", nom_env, e, defaut);
+                    defaut
+                }
+            },
+            Err(_) => defaut
+        }
+    }
+}
+
+/// Limiteur de debit des soumissions publiques avec une fenetre glissante.
+/// Les compteurs sont conserves dans redis (partages entre instances) lorsque MG_REDIS_URL est
+/// configure. Sinon, ou si redis n'est pas disponible, les compteurs sont conserves en memoire.
+pub struct LimiteurDebit {
+    pub limite_globale: LimiteDebit,
+    /// Limite par source (fingerprint du certificat de l'emetteur) lorsque l'application n'en
+    /// configure pas.
+    pub limite_source_defaut: LimiteDebit,
+    /// Limites de l'emission des jetons de formulaire (globale et par source)
+    pub limite_jetons_globale: LimiteDebit,
+    pub limite_jetons_source: LimiteDebit,
+    client_redis: Option<redis::Client>,
+    connexion_redis: MutexAsync<Option<MultiplexedConnection>>,
+    prochaine_connexion_redis: Mutex<i64>,
+    /// Fenetres en memoire : (horodatage ms, sequence) de chaque soumission
+    fenetres: Mutex<HashMap<String, VecDeque<(i64, u64)>>>,
+    sequence: AtomicU64,
+}
+
+impl Debug for LimiteurDebit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LimiteurDebit")
+            .field("limite_globale", &self.limite_globale)
+            .field("limite_source_defaut", &self.limite_source_defaut)
+            .field("limite_jetons_globale", &self.limite_jetons_globale)
+            .field("limite_jetons_source", &self.limite_jetons_source)
+            .field("redis", &self.client_redis.is_some())
+            .finish()
+    }
+}
+
+impl LimiteurDebit {
+
+    pub fn new() -> Self {
+        let limite_globale = LimiteDebit::charger_env(ENV_DEBIT_GLOBAL, LimiteDebit {
+            nombre: DEBIT_GLOBAL_DEFAUT_NOMBRE, fenetre_secondes: DEBIT_GLOBAL_DEFAUT_SECONDES });
+        let limite_source_defaut = LimiteDebit::charger_env(ENV_DEBIT_SOURCE, LimiteDebit {
+            nombre: DEBIT_SOURCE_DEFAUT_NOMBRE, fenetre_secondes: DEBIT_SOURCE_DEFAUT_SECONDES });
+        let limite_jetons_globale = LimiteDebit::charger_env(ENV_DEBIT_JETONS_GLOBAL, LimiteDebit {
+            nombre: DEBIT_JETONS_GLOBAL_DEFAUT_NOMBRE, fenetre_secondes: DEBIT_JETONS_GLOBAL_DEFAUT_SECONDES });
+        let limite_jetons_source = LimiteDebit::charger_env(ENV_DEBIT_JETONS_SOURCE, LimiteDebit {
+            nombre: DEBIT_JETONS_SOURCE_DEFAUT_NOMBRE, fenetre_secondes: DEBIT_JETONS_SOURCE_DEFAUT_SECONDES });
+
+        let client_redis = match std::env::var(ENV_REDIS_URL) {
+            Ok(url) => match preparer_client_redis(url.as_str()) {
+                Ok(inner) => {
+                    info!("LimiteurDebit Compteurs de debit conserves dans redis");
+                    Some(inner)
+                },
+                Err(e) => {
+                    warn!("LimiteurDebit Erreur configuration redis, compteurs en memoire : {}", e);
+                    None
+                }
+            },
+            Err(_) => {
+                info!("LimiteurDebit {} absent, compteurs de debit en memoire", ENV_REDIS_URL);
+                None
+            }
+        };
+
+        Self {
+            limite_globale,
+            limite_source_defaut,
+            limite_jetons_globale,
+            limite_jetons_source,
+            client_redis,
+            connexion_redis: MutexAsync::new(None),
+            prochaine_connexion_redis: Mutex::new(0),
+            fenetres: Mutex::new(HashMap::new()),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Enregistre une soumission pour la cle et retourne false si la limite est depassee.
+    /// Les soumissions refusees restent dans la fenetre : une source qui insiste reste bloquee.
+    /// A utiliser seulement pour une cle propre a la source.
+    pub async fn verifier(&self, cle: &str, limite: &LimiteDebit) -> bool {
+        let maintenant = Utc::now().timestamp_millis();
+        let fenetre_ms = limite.fenetre_secondes as i64 * 1000;
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+
+        let nombre = match self.get_connexion_redis().await {
+            Some(mut connexion) => match self.ajouter_redis(&mut connexion, cle, maintenant, fenetre_ms, sequence).await {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("LimiteurDebit.verifier Erreur redis, utiliser compteurs en memoire : {:?}", e);
+                    self.reset_connexion_redis().await;
+                    self.ajouter_memoire(cle, maintenant, fenetre_ms, sequence, None).unwrap_or(0)
+                }
+            },
+            None => self.ajouter_memoire(cle, maintenant, fenetre_ms, sequence, None).unwrap_or(0)
+        };
+
+        debug!("LimiteurDebit.verifier Cle {} : {}/{}", cle, nombre, limite.nombre);
+        nombre <= limite.nombre as u64
+    }
+
+    /// Reserve une place dans la fenetre seulement si la limite n'est pas atteinte. Une tentative
+    /// refusee n'est pas enregistree. Retourne l'identificateur de la reservation (annuler).
+    pub async fn reserver(&self, cle: &str, limite: &LimiteDebit) -> Option<String> {
+        let maintenant = Utc::now().timestamp_millis();
+        let fenetre_ms = limite.fenetre_secondes as i64 * 1000;
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+
+        let reserve = match self.get_connexion_redis().await {
+            Some(mut connexion) => match self.reserver_redis(&mut connexion, cle, maintenant, fenetre_ms, sequence, limite.nombre).await {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("LimiteurDebit.reserver Erreur redis, utiliser compteurs en memoire : {:?}", e);
+                    self.reset_connexion_redis().await;
+                    self.ajouter_memoire(cle, maintenant, fenetre_ms, sequence, Some(limite.nombre)).is_some()
+                }
+            },
+            None => self.ajouter_memoire(cle, maintenant, fenetre_ms, sequence, Some(limite.nombre)).is_some()
+        };
+
+        debug!("LimiteurDebit.reserver Cle {} : {}", cle, reserve);
+        match reserve {
+            true => Some(format!("{}-{}", maintenant, sequence)),
+            false => None
+        }
+    }
+
+    /// Retire une reservation (soumission refusee par une autre limite).
+    pub async fn annuler(&self, cle: &str, reservation: &str) {
+        if let Some(mut connexion) = self.get_connexion_redis().await {
+            let cle_redis = format!("{}:{}", PREFIXE_CLE_REDIS_DEBIT, cle);
+            let resultat: Result<u64, redis::RedisError> = redis::cmd("ZREM")
+                .arg(&cle_redis).arg(reservation)
+                .query_async(&mut connexion).await;
+            if let Err(e) = resultat {
+                warn!("LimiteurDebit.annuler Erreur redis : {:?}", e);
+            }
+        }
+        let mut guard = self.fenetres.lock().expect("lock fenetres");
+        if let Some(fenetre) = guard.get_mut(cle) {
+            fenetre.retain(|(horodatage, sequence)| format!("{}-{}", horodatage, sequence).as_str() != reservation);
+        }
+    }
+
+    /// Retire les fenetres expirees des compteurs en memoire.
+    pub fn nettoyer(&self) {
+        let limite = Utc::now().timestamp_millis() - DEBIT_FENETRE_MAXIMUM_SECONDES as i64 * 1000;
+        let mut guard = self.fenetres.lock().expect("lock fenetres");
+        guard.retain(|_, fenetre| match fenetre.back() {
+            Some((derniere, _)) => *derniere > limite,
+            None => false
+        });
+    }
+
+    /// Ajoute une soumission a la fenetre en memoire et retourne le nombre de soumissions dans la
+    /// fenetre. Avec un maximum, la soumission n'est pas ajoutee (None) lorsque la fenetre est pleine.
+    fn ajouter_memoire(&self, cle: &str, maintenant: i64, fenetre_ms: i64, sequence: u64, maximum: Option<u32>) -> Option<u64> {
+        let mut guard = self.fenetres.lock().expect("lock fenetres");
+        let fenetre = guard.entry(cle.to_owned()).or_insert_with(VecDeque::new);
+        while let Some((plus_ancien, _)) = fenetre.front() {
+            if *plus_ancien > maintenant - fenetre_ms { break }
+            fenetre.pop_front();
+        }
+        if let Some(maximum) = maximum {
+            if fenetre.len() as u64 >= maximum as u64 { return None }
+        }
+        fenetre.push_back((maintenant, sequence));
+        Some(fenetre.len() as u64)
+    }
+
+    async fn ajouter_redis(&self, connexion: &mut MultiplexedConnection, cle: &str, maintenant: i64, fenetre_ms: i64, sequence: u64)
+        -> Result<u64, redis::RedisError>
+    {
+        let cle_redis = format!("{}:{}", PREFIXE_CLE_REDIS_DEBIT, cle);
+        let membre = format!("{}-{}", maintenant, sequence);
+        let (nombre,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE").arg(&cle_redis).arg("-inf").arg(maintenant - fenetre_ms).ignore()
+            .cmd("ZADD").arg(&cle_redis).arg(maintenant).arg(membre).ignore()
+            .cmd("ZCARD").arg(&cle_redis)
+            .cmd("PEXPIRE").arg(&cle_redis).arg(fenetre_ms).ignore()
+            .query_async(connexion)
+            .await?;
+        Ok(nombre)
+    }
+
+    async fn reserver_redis(&self, connexion: &mut MultiplexedConnection, cle: &str, maintenant: i64, fenetre_ms: i64,
+                            sequence: u64, maximum: u32)
+        -> Result<bool, redis::RedisError>
+    {
+        let cle_redis = format!("{}:{}", PREFIXE_CLE_REDIS_DEBIT, cle);
+        let membre = format!("{}-{}", maintenant, sequence);
+        let reserve: i64 = redis::Script::new(SCRIPT_RESERVER_REDIS)
+            .key(&cle_redis)
+            .arg(maintenant).arg(fenetre_ms).arg(maximum).arg(membre)
+            .invoke_async(connexion)
+            .await?;
+        Ok(reserve == 1)
+    }
+
+    async fn get_connexion_redis(&self) -> Option<MultiplexedConnection> {
+        let client = self.client_redis.as_ref()?;
+        let mut guard = self.connexion_redis.lock().await;
+        if let Some(connexion) = guard.as_ref() {
+            return Some(connexion.clone())
+        }
+
+        // Eviter de tenter une connexion a chaque soumission lorsque redis est hors ligne
+        let maintenant = Utc::now().timestamp();
+        {
+            let mut prochaine = self.prochaine_connexion_redis.lock().expect("lock prochaine_connexion_redis");
+            if *prochaine > maintenant { return None }
+            *prochaine = maintenant + DEBIT_REDIS_DELAI_RECONNEXION_SECONDES;
+        }
+
+        match client.get_multiplexed_tokio_connection().await {
+            Ok(connexion) => {
+                debug!("LimiteurDebit.get_connexion_redis Connexion redis etablie");
+                *guard = Some(connexion.clone());
+                Some(connexion)
+            },
+            Err(e) => {
+                warn!("LimiteurDebit.get_connexion_redis Erreur connexion redis : {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn reset_connexion_redis(&self) {
+        let mut guard = self.connexion_redis.lock().await;
+        *guard = None;
+    }
+}
+
+fn preparer_client_redis(url: &str) -> Result<redis::Client, String> {
+    let mut info = match url.into_connection_info() {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("limiteur.preparer_client_redis Url invalide : {:?}", e))?
+    };
+    if let Ok(fichier) = std::env::var(ENV_REDIS_PASSWORD_FILE) {
+        match std::fs::read_to_string(fichier.as_str()) {
+            Ok(password) => info.redis.password = Some(password.trim().to_owned()),
+            Err(e) => Err(format!("limiteur.preparer_client_redis Erreur lecture {} : {:?}", fichier, e))?
+        }
+    }
+    match redis::Client::open(info) {
+        Ok(inner) => Ok(inner),
+        Err(e) => Err(format!("limiteur.preparer_client_redis Erreur client redis : {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod test_limiteur {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_parse_limite() {
+        setup("test_parse_limite");
+        assert_eq!(LimiteDebit { nombre: 10, fenetre_secondes: 60 }, LimiteDebit::parse("10/60").unwrap());
+        assert_eq!(LimiteDebit { nombre: 10, fenetre_secondes: 60 }, LimiteDebit::parse(" 10 / 60 ").unwrap());
+    }
+
+    #[test]
+    fn test_parse_limite_invalide() {
+        setup("test_parse_limite_invalide");
+        assert!(LimiteDebit::parse("").is_err());
+        assert!(LimiteDebit::parse("10").is_err());
+        assert!(LimiteDebit::parse("10/").is_err());
+        assert!(LimiteDebit::parse("a/b").is_err());
+        assert!(LimiteDebit::parse("10/60/5").is_err());
+        assert!(LimiteDebit::parse("-1/60").is_err());
+        // Valeurs hors limites (valider)
+        assert!(LimiteDebit::parse("10/0").is_err());
+        assert!(LimiteDebit::parse("0/60").is_err());
+        assert!(LimiteDebit::parse(format!("10/{}", DEBIT_FENETRE_MAXIMUM_SECONDES + 1).as_str()).is_err());
+    }
+
+    #[test]
+    fn test_ajouter_memoire_fenetre() {
+        setup("test_ajouter_memoire_fenetre");
+        let limiteur = LimiteurDebit::new();
+        assert_eq!(Some(1), limiteur.ajouter_memoire("cle", 0, 1000, 1, None));
+        assert_eq!(Some(2), limiteur.ajouter_memoire("cle", 500, 1000, 2, None));
+        // La soumission a 0 expire a 1000 (fenetre de 1000 ms)
+        assert_eq!(Some(2), limiteur.ajouter_memoire("cle", 1000, 1000, 3, None));
+        assert_eq!(Some(2), limiteur.ajouter_memoire("cle", 1600, 1000, 4, None));
+        // Toutes les soumissions expirees
+        assert_eq!(Some(1), limiteur.ajouter_memoire("cle", 5000, 1000, 5, None));
+        // Les cles sont independantes
+        assert_eq!(Some(1), limiteur.ajouter_memoire("autre", 5000, 1000, 6, None));
+    }
+
+    #[test]
+    fn test_ajouter_memoire_maximum() {
+        setup("test_ajouter_memoire_maximum");
+        let limiteur = LimiteurDebit::new();
+        assert_eq!(Some(1), limiteur.ajouter_memoire("cle", 0, 1000, 1, Some(2)));
+        assert_eq!(Some(2), limiteur.ajouter_memoire("cle", 100, 1000, 2, Some(2)));
+        // Fenetre pleine, la tentative refusee n'est pas enregistree
+        assert_eq!(None, limiteur.ajouter_memoire("cle", 200, 1000, 3, Some(2)));
+        assert_eq!(None, limiteur.ajouter_memoire("cle", 300, 1000, 4, Some(2)));
+        // Une place se libere lorsque la premiere soumission expire
+        assert_eq!(Some(2), limiteur.ajouter_memoire("cle", 1000, 1000, 5, Some(2)));
+        assert_eq!(Some(3), limiteur.ajouter_memoire("cle", 1000, 1000, 6, None));
+    }
+}
@@ -6,6 +6,10 @@ mod evenements;
 mod requetes;
 mod transactions;
 mod common;
+mod registre;
+mod limiteur;
+mod spam;
+mod messagerie;
 
 use crate::domaine::run;
 
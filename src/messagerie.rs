@@ -0,0 +1,205 @@
+use std::error::Error;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::common_messages::verifier_reponse_ok;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json::json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::{DocApplication, DocMessage};
+use crate::constantes::*;
+
+/// Etat du transfert d'un message vers la boite de Messagerie du proprietaire.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtatTransfert {
+    Attente,
+    Transfere,
+    /// Nombre maximal de tentatives atteint
+    Echec,
+    /// Message supprime ou transfert desactive avant la livraison
+    Annule,
+}
+
+/// Suivi du transfert d'un message (collection transferts). La collection n'est pas regeneree :
+/// les messages deja livres ne sont pas transferes a nouveau apres une regeneration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocTransfert {
+    pub message_id: String,
+    pub application_id: String,
+    pub user_id: String,
+    pub etat: EtatTransfert,
+    pub tentatives: u32,
+    pub prochaine_tentative: i64,
+    pub date_transfert: Option<i64>,
+    pub erreur: Option<String>,
+}
+
+/// Ajoute un message a transferer. Le transfert est fait par l'entretien du domaine.
+pub async fn ajouter_transfert<M>(middleware: &M, message_id: &str, application_id: &str, user_id: &str)
+    -> Result<(), String>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_MESSAGE_ID: message_id };
+    let ops = doc! {
+        "$setOnInsert": {
+            CHAMP_MESSAGE_ID: message_id,
+            CHAMP_APPLICATION_ID: application_id,
+            CHAMP_USER_ID: user_id,
+            CHAMP_ETAT: "attente",
+            CHAMP_TENTATIVES: 0,
+            CHAMP_PROCHAINE_TENTATIVE: Utc::now().timestamp(),
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSFERTS)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    match collection.update_one(filtre, ops, options).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("messagerie.ajouter_transfert Erreur ajout transfert message {} : {:?}", message_id, e))
+    }
+}
+
+/// Transfere les messages en attente dont la prochaine tentative est due.
+pub async fn transferer_messages<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let maintenant = Utc::now().timestamp();
+    let filtre = doc! { CHAMP_ETAT: "attente", CHAMP_PROCHAINE_TENTATIVE: {"$lte": maintenant} };
+    let options = FindOptions::builder()
+        .sort(doc! { CHAMP_PROCHAINE_TENTATIVE: 1 })
+        .limit(TAILLE_LOT_TRANSFERTS)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSFERTS)?;
+    let mut transferts = Vec::new();
+    let mut curseur = collection.find(filtre, options).await?;
+    while let Some(d) = curseur.next().await {
+        let transfert: DocTransfert = convertir_bson_deserializable(d?)?;
+        transferts.push(transfert);
+    }
+
+    for transfert in transferts {
+        let filtre = doc! { CHAMP_MESSAGE_ID: &transfert.message_id };
+        let set_ops = match transferer_message(middleware, &transfert).await {
+            Ok(true) => {
+                debug!("transferer_messages Message {} transfere", transfert.message_id);
+                doc! { CHAMP_ETAT: "transfere", CHAMP_DATE_TRANSFERT: Utc::now().timestamp() }
+            },
+            Ok(false) => {
+                debug!("transferer_messages Transfert message {} annule", transfert.message_id);
+                doc! { CHAMP_ETAT: "annule" }
+            },
+            Err(e) => {
+                let tentatives = transfert.tentatives + 1;
+                if tentatives >= TRANSFERT_TENTATIVES_MAXIMUM {
+                    warn!("transferer_messages Echec transfert message {} apres {} tentatives : {}", transfert.message_id, tentatives, e);
+                    doc! { CHAMP_ETAT: "echec", CHAMP_TENTATIVES: tentatives, CHAMP_ERREUR: e }
+                } else {
+                    info!("transferer_messages Erreur transfert message {} (tentative {}) : {}", transfert.message_id, tentatives, e);
+                    let delai = (TRANSFERT_DELAI_REPRISE_SECONDES << (tentatives - 1).min(16))
+                        .min(TRANSFERT_DELAI_REPRISE_MAXIMUM_SECONDES);
+                    doc! {
+                        CHAMP_TENTATIVES: tentatives,
+                        CHAMP_PROCHAINE_TENTATIVE: Utc::now().timestamp() + delai,
+                        CHAMP_ERREUR: e,
+                    }
+                }
+            }
+        };
+        let ops = doc! { "$set": set_ops, "$currentDate": { CHAMP_MODIFICATION: true } };
+        collection.update_one(filtre, ops, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Sauvegarde la cle du message pour le domaine Messagerie et livre le message au proprietaire.
+/// Retourne false lorsque le transfert n'est plus requis (message supprime, transfert desactive)
+/// ou impossible (cle Messagerie non fournie par le client).
+async fn transferer_message<M>(middleware: &M, transfert: &DocTransfert) -> Result<bool, String>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_APPLICATION_ID: &transfert.application_id, CHAMP_USER_ID: &transfert.user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application: DocApplication = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("messagerie.transferer_message Erreur mapping application : {:?}", e))?
+        },
+        Ok(None) => return Ok(false),
+        Err(e) => Err(format!("messagerie.transferer_message Erreur chargement application : {:?}", e))?
+    };
+    if application.transfert_messagerie != Some(true) || application.supprime == Some(true) {
+        return Ok(false)
+    }
+
+    let filtre = doc! { CHAMP_MESSAGE_ID: &transfert.message_id, CHAMP_USER_ID: &transfert.user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let message: DocMessage = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("messagerie.transferer_message Erreur mapping message : {:?}", e))?
+        },
+        Ok(None) => return Ok(false),
+        Err(e) => Err(format!("messagerie.transferer_message Erreur chargement message : {:?}", e))?
+    };
+    if message.quarantaine == Some(true) {
+        return Ok(false)
+    }
+
+    // Le message et ses fichiers joints sont chiffres avec la meme cle. Le client a fourni la
+    // commande de sauvegarde de cette cle pour le domaine Messagerie avec la soumission.
+    let cle_messagerie = match message.cle_messagerie.as_ref() {
+        Some(inner) => inner,
+        None => {
+            warn!("messagerie.transferer_message Message {} sans cle pour {}, transfert impossible", message.message_id, DOMAINE_MESSAGERIE_NOM);
+            return Ok(false)
+        }
+    };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
+        .exchanges(vec![Securite::L4Secure])
+        .build();
+    match middleware.transmettre_commande(routage, cle_messagerie, true).await {
+        Ok(Some(reponse)) => if ! verifier_reponse_ok(&reponse) {
+            Err(format!("messagerie.transferer_message Sauvegarde cle {} refusee", DOMAINE_MESSAGERIE_NOM))?
+        },
+        Ok(None) => Err(format!("messagerie.transferer_message Sauvegarde cle - aucune reponse"))?,
+        Err(e) => Err(format!("messagerie.transferer_message Erreur sauvegarde cle : {:?}", e))?
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_MESSAGERIE_NOM, COMMANDE_MESSAGERIE_RECEVOIR)
+        .exchanges(vec![Securite::L2Prive])
+        .build();
+    let commande_message = json!({
+        "destinataires": [&message.user_id],
+        "message": {
+            "origine": DOMAINE_NOM,
+            "message_id": &message.message_id,
+            "application_id": &message.application_id,
+            "nom_application": &application.nom,
+            "data_chiffre": &message.data_chiffre,
+            "format": &message.format,
+            "header": &message.header,
+            "hachage_bytes": &message.ref_hachage_bytes,
+            "enveloppe": &message.enveloppe,
+            "fichiers": &message.fichiers,
+            "date_reception": message.date_reception,
+        },
+    });
+    match middleware.transmettre_commande(routage, &commande_message, true).await {
+        Ok(Some(reponse)) => if ! verifier_reponse_ok(&reponse) {
+            Err(format!("messagerie.transferer_message Message refuse par {}", DOMAINE_MESSAGERIE_NOM))?
+        },
+        Ok(None) => Err(format!("messagerie.transferer_message Transfert message - aucune reponse"))?,
+        Err(e) => Err(format!("messagerie.transferer_message Erreur transfert message : {:?}", e))?
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,363 @@
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::recepteur_messages::MessageValideAction;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+
+/// Type de message recu par le domaine (premier element de la routing key).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TypeAction {
+    Requete,
+    Commande,
+    Transaction,
+    Evenement,
+}
+
+impl TypeAction {
+    pub fn prefixe_routing_key(&self) -> &'static str {
+        match self {
+            TypeAction::Requete => "requete",
+            TypeAction::Commande => "commande",
+            TypeAction::Transaction => "transaction",
+            TypeAction::Evenement => "evenement",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerRequete {
+    GetListeApplications,
+    GetApplication,
+    GetMessages,
+    GetMessage,
+    GetClesMessages,
+    GetActions,
+    GetApplicationPublique,
+    GetVersionsApplication,
+    GetStatistiquesApplication,
+    GetDefiSoumission,
+    GetBlocages,
+    GetFichiersManquants,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerCommande {
+    CreerNouvelleApplication,
+    SauvegarderApplication,
+    SauvegarderPageApplication,
+    PublierApplication,
+    RestaurerVersionApplication,
+    SauvegarderHoraireApplication,
+    SauvegarderLimiteSoumissions,
+    SauvegarderLimiteDebit,
+    SauvegarderDifficulteDefi,
+    SauvegarderConfigurationSpam,
+    LibererMessages,
+    SupprimerMessages,
+    ChangerEtatMessages,
+    EtiqueterMessages,
+    AssignerMessages,
+    BloquerSource,
+    DebloquerSource,
+    SauvegarderLimitesFichiers,
+    SauvegarderTransfertMessagerie,
+    SupprimerApplication,
+    ArchiverApplication,
+    RestaurerApplication,
+    SoumettreMessage,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerTransaction {
+    AppliquerHoraireApplication,
+    PurgerApplications,
+    SupprimerApplicationsUsager,
+    CreerNouvelleApplication,
+    SauvegarderApplication,
+    SauvegarderPageApplication,
+    PublierApplication,
+    RestaurerVersionApplication,
+    SauvegarderHoraireApplication,
+    SauvegarderLimiteSoumissions,
+    SauvegarderLimiteDebit,
+    SauvegarderDifficulteDefi,
+    SauvegarderConfigurationSpam,
+    LibererMessages,
+    SupprimerMessages,
+    ChangerEtatMessages,
+    EtiqueterMessages,
+    AssignerMessages,
+    BloquerSource,
+    DebloquerSource,
+    SauvegarderLimitesFichiers,
+    SauvegarderTransfertMessagerie,
+    SupprimerApplication,
+    ArchiverApplication,
+    RestaurerApplication,
+    SoumettreMessage,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerEvenement {
+    FichiersConsigne,
+    ConfirmerEtatFuuids,
+    SupprimerUsager,
+}
+
+/// Handler d'une action. Le type de handler determine le type d'action (requete, commande, ...).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handler {
+    Requete(HandlerRequete),
+    Commande(HandlerCommande),
+    Transaction(HandlerTransaction),
+    Evenement(HandlerEvenement),
+}
+
+impl Handler {
+    pub fn type_action(&self) -> TypeAction {
+        match self {
+            Handler::Requete(_) => TypeAction::Requete,
+            Handler::Commande(_) => TypeAction::Commande,
+            Handler::Transaction(_) => TypeAction::Transaction,
+            Handler::Evenement(_) => TypeAction::Evenement,
+        }
+    }
+}
+
+/// Regle d'autorisation. Le message est accepte s'il satisfait au moins une des conditions.
+pub struct Autorisation {
+    /// Exchanges acceptes pour l'action
+    pub exchanges: &'static [Securite],
+    /// Roles acceptes pour un certificat usager (user_id requis)
+    pub roles: &'static [RolesCertificats],
+    /// Accepter un certificat avec delegation globale proprietaire
+    pub delegation_globale: bool,
+}
+
+/// Definition d'une action du domaine. Sert a lier les Q, autoriser et aiguiller les messages.
+pub struct DefinitionAction {
+    /// Domaine de la routing key (DOMAINE_NOM sauf pour les evenements d'autres domaines)
+    pub domaine: &'static str,
+    pub action: &'static str,
+    /// Exchanges sur lesquels la routing key est liee
+    pub exchanges: &'static [Securite],
+    pub autorisation: Autorisation,
+    pub handler: Handler,
+}
+
+impl DefinitionAction {
+    pub fn type_action(&self) -> TypeAction {
+        self.handler.type_action()
+    }
+
+    pub fn routing_key(&self) -> String {
+        format!("{}.{}.{}", self.type_action().prefixe_routing_key(), self.domaine, self.action)
+    }
+}
+
+const EXCHANGES_PUBLICS: &[Securite] = &[Securite::L1Public, Securite::L2Prive, Securite::L3Protege, Securite::L4Secure];
+const EXCHANGES_PRIVES: &[Securite] = &[Securite::L2Prive, Securite::L3Protege];
+const ROLES_USAGER: &[RolesCertificats] = &[RolesCertificats::ComptePrive];
+const AUCUN_ROLE: &[RolesCertificats] = &[];
+
+/// Requete d'un usager (2.prive), aussi acceptee des composants 3.protege.
+const fn requete_usager(action: &'static str, handler: HandlerRequete) -> DefinitionAction {
+    DefinitionAction {
+        domaine: DOMAINE_NOM,
+        action,
+        exchanges: &[Securite::L2Prive],
+        autorisation: Autorisation { exchanges: EXCHANGES_PRIVES, roles: ROLES_USAGER, delegation_globale: true },
+        handler: Handler::Requete(handler),
+    }
+}
+
+/// Requete d'un visiteur (1.public).
+const fn requete_publique(action: &'static str, handler: HandlerRequete) -> DefinitionAction {
+    DefinitionAction {
+        domaine: DOMAINE_NOM,
+        action,
+        exchanges: &[Securite::L1Public],
+        autorisation: Autorisation { exchanges: EXCHANGES_PUBLICS, roles: AUCUN_ROLE, delegation_globale: false },
+        handler: Handler::Requete(handler),
+    }
+}
+
+/// Commande du proprietaire d'une application (compte prive ou delegation globale). Comme avant le
+/// registre, les commandes des composants sont acceptees sur tous les exchanges ; le handler
+/// verifie ensuite le proprietaire.
+const fn commande_usager(action: &'static str, handler: HandlerCommande) -> DefinitionAction {
+    DefinitionAction {
+        domaine: DOMAINE_NOM,
+        action,
+        exchanges: &[Securite::L2Prive],
+        autorisation: Autorisation { exchanges: EXCHANGES_PUBLICS, roles: ROLES_USAGER, delegation_globale: true },
+        handler: Handler::Commande(handler),
+    }
+}
+
+/// Commande d'un visiteur (1.public).
+const fn commande_publique(action: &'static str, handler: HandlerCommande) -> DefinitionAction {
+    DefinitionAction {
+        domaine: DOMAINE_NOM,
+        action,
+        exchanges: &[Securite::L1Public],
+        autorisation: Autorisation { exchanges: EXCHANGES_PUBLICS, roles: AUCUN_ROLE, delegation_globale: false },
+        handler: Handler::Commande(handler),
+    }
+}
+
+/// Transaction 4.secure, doit etre validee par une commande.
+const fn transaction(action: &'static str, handler: HandlerTransaction) -> DefinitionAction {
+    DefinitionAction {
+        domaine: DOMAINE_NOM,
+        action,
+        exchanges: &[Securite::L4Secure],
+        autorisation: Autorisation { exchanges: &[Securite::L4Secure], roles: AUCUN_ROLE, delegation_globale: false },
+        handler: Handler::Transaction(handler),
+    }
+}
+
+/// Evenement emis par un domaine tiers.
+const fn evenement(domaine: &'static str, action: &'static str, exchanges: &'static [Securite], handler: HandlerEvenement)
+    -> DefinitionAction
+{
+    DefinitionAction {
+        domaine,
+        action,
+        exchanges,
+        autorisation: Autorisation { exchanges, roles: AUCUN_ROLE, delegation_globale: false },
+        handler: Handler::Evenement(handler),
+    }
+}
+
+/// Registre de toutes les actions du domaine.
+pub const REGISTRE_ACTIONS: &[DefinitionAction] = &[
+    // Requetes
+    requete_usager(REQUETE_LISTE_APPLICATIONS, HandlerRequete::GetListeApplications),
+    requete_usager(REQUETE_APPLICATION, HandlerRequete::GetApplication),
+    requete_usager(REQUETE_MESSAGES, HandlerRequete::GetMessages),
+    requete_usager(REQUETE_MESSAGE, HandlerRequete::GetMessage),
+    requete_usager(REQUETE_CLES_MESSAGES, HandlerRequete::GetClesMessages),
+    requete_usager(REQUETE_VERSIONS_APPLICATION, HandlerRequete::GetVersionsApplication),
+    requete_usager(REQUETE_STATISTIQUES_APPLICATION, HandlerRequete::GetStatistiquesApplication),
+    requete_usager(REQUETE_BLOCAGES, HandlerRequete::GetBlocages),
+    requete_usager(REQUETE_FICHIERS_MANQUANTS, HandlerRequete::GetFichiersManquants),
+    requete_usager(REQUETE_ACTIONS, HandlerRequete::GetActions),
+
+    // Requetes publiques
+    requete_publique(REQUETE_APPLICATION_PUBLIQUE, HandlerRequete::GetApplicationPublique),
+    requete_publique(REQUETE_DEFI_SOUMISSION, HandlerRequete::GetDefiSoumission),
+
+    // Commandes usager (proprietaire de l'application)
+    commande_usager(TRANSACTION_CREER_NOUVELLE_APPLICATION, HandlerCommande::CreerNouvelleApplication),
+    commande_usager(TRANSACTION_SAUVEGARDER_APPLICATION, HandlerCommande::SauvegarderApplication),
+    commande_usager(TRANSACTION_SAUVEGARDER_PAGE_APPLICATION, HandlerCommande::SauvegarderPageApplication),
+    commande_usager(TRANSACTION_PUBLIER_APPLICATION, HandlerCommande::PublierApplication),
+    commande_usager(TRANSACTION_RESTAURER_VERSION_APPLICATION, HandlerCommande::RestaurerVersionApplication),
+    commande_usager(TRANSACTION_SAUVEGARDER_HORAIRE_APPLICATION, HandlerCommande::SauvegarderHoraireApplication),
+    commande_usager(TRANSACTION_SAUVEGARDER_LIMITE_SOUMISSIONS, HandlerCommande::SauvegarderLimiteSoumissions),
+    commande_usager(TRANSACTION_SAUVEGARDER_LIMITE_DEBIT, HandlerCommande::SauvegarderLimiteDebit),
+    commande_usager(TRANSACTION_SAUVEGARDER_DIFFICULTE_DEFI, HandlerCommande::SauvegarderDifficulteDefi),
+    commande_usager(TRANSACTION_SAUVEGARDER_CONFIGURATION_SPAM, HandlerCommande::SauvegarderConfigurationSpam),
+    commande_usager(TRANSACTION_LIBERER_MESSAGES, HandlerCommande::LibererMessages),
+    commande_usager(TRANSACTION_SUPPRIMER_MESSAGES, HandlerCommande::SupprimerMessages),
+    commande_usager(TRANSACTION_CHANGER_ETAT_MESSAGES, HandlerCommande::ChangerEtatMessages),
+    commande_usager(TRANSACTION_ETIQUETER_MESSAGES, HandlerCommande::EtiqueterMessages),
+    commande_usager(TRANSACTION_ASSIGNER_MESSAGES, HandlerCommande::AssignerMessages),
+    commande_usager(TRANSACTION_BLOQUER_SOURCE, HandlerCommande::BloquerSource),
+    commande_usager(TRANSACTION_DEBLOQUER_SOURCE, HandlerCommande::DebloquerSource),
+    commande_usager(TRANSACTION_SAUVEGARDER_LIMITES_FICHIERS, HandlerCommande::SauvegarderLimitesFichiers),
+    commande_usager(TRANSACTION_SAUVEGARDER_TRANSFERT_MESSAGERIE, HandlerCommande::SauvegarderTransfertMessagerie),
+    commande_usager(TRANSACTION_SUPPRIMER_APPLICATION, HandlerCommande::SupprimerApplication),
+    commande_usager(TRANSACTION_ARCHIVER_APPLICATION, HandlerCommande::ArchiverApplication),
+    commande_usager(TRANSACTION_RESTAURER_APPLICATION, HandlerCommande::RestaurerApplication),
+
+    // Commandes publiques
+    commande_publique(TRANSACTION_SOUMETTRE_MESSAGE, HandlerCommande::SoumettreMessage),
+
+    // Transactions
+    transaction(TRANSACTION_CREER_NOUVELLE_APPLICATION, HandlerTransaction::CreerNouvelleApplication),
+    transaction(TRANSACTION_SAUVEGARDER_APPLICATION, HandlerTransaction::SauvegarderApplication),
+    transaction(TRANSACTION_SAUVEGARDER_PAGE_APPLICATION, HandlerTransaction::SauvegarderPageApplication),
+    transaction(TRANSACTION_PUBLIER_APPLICATION, HandlerTransaction::PublierApplication),
+    transaction(TRANSACTION_RESTAURER_VERSION_APPLICATION, HandlerTransaction::RestaurerVersionApplication),
+    transaction(TRANSACTION_SAUVEGARDER_HORAIRE_APPLICATION, HandlerTransaction::SauvegarderHoraireApplication),
+    transaction(TRANSACTION_SAUVEGARDER_LIMITE_SOUMISSIONS, HandlerTransaction::SauvegarderLimiteSoumissions),
+    transaction(TRANSACTION_SAUVEGARDER_LIMITE_DEBIT, HandlerTransaction::SauvegarderLimiteDebit),
+    transaction(TRANSACTION_SAUVEGARDER_DIFFICULTE_DEFI, HandlerTransaction::SauvegarderDifficulteDefi),
+    transaction(TRANSACTION_SAUVEGARDER_CONFIGURATION_SPAM, HandlerTransaction::SauvegarderConfigurationSpam),
+    transaction(TRANSACTION_LIBERER_MESSAGES, HandlerTransaction::LibererMessages),
+    transaction(TRANSACTION_SUPPRIMER_MESSAGES, HandlerTransaction::SupprimerMessages),
+    transaction(TRANSACTION_CHANGER_ETAT_MESSAGES, HandlerTransaction::ChangerEtatMessages),
+    transaction(TRANSACTION_ETIQUETER_MESSAGES, HandlerTransaction::EtiqueterMessages),
+    transaction(TRANSACTION_ASSIGNER_MESSAGES, HandlerTransaction::AssignerMessages),
+    transaction(TRANSACTION_BLOQUER_SOURCE, HandlerTransaction::BloquerSource),
+    transaction(TRANSACTION_DEBLOQUER_SOURCE, HandlerTransaction::DebloquerSource),
+    transaction(TRANSACTION_SAUVEGARDER_LIMITES_FICHIERS, HandlerTransaction::SauvegarderLimitesFichiers),
+    transaction(TRANSACTION_SAUVEGARDER_TRANSFERT_MESSAGERIE, HandlerTransaction::SauvegarderTransfertMessagerie),
+    // Emises par le domaine (cedule, entretien), aucune commande correspondante
+    transaction(TRANSACTION_APPLIQUER_HORAIRE_APPLICATION, HandlerTransaction::AppliquerHoraireApplication),
+    transaction(TRANSACTION_PURGER_APPLICATIONS, HandlerTransaction::PurgerApplications),
+    transaction(TRANSACTION_SUPPRIMER_APPLICATIONS_USAGER, HandlerTransaction::SupprimerApplicationsUsager),
+    transaction(TRANSACTION_SUPPRIMER_APPLICATION, HandlerTransaction::SupprimerApplication),
+    transaction(TRANSACTION_ARCHIVER_APPLICATION, HandlerTransaction::ArchiverApplication),
+    transaction(TRANSACTION_RESTAURER_APPLICATION, HandlerTransaction::RestaurerApplication),
+    transaction(TRANSACTION_SOUMETTRE_MESSAGE, HandlerTransaction::SoumettreMessage),
+
+    // Evenements
+    evenement(DOMAINE_FICHIERS_NOM, EVENEMENT_FICHIERS_CONSIGNE, &[Securite::L2Prive], HandlerEvenement::FichiersConsigne),
+    evenement(DOMAINE_FICHIERS_NOM, EVENEMENT_CONFIRMER_ETAT_FUUIDS, &[Securite::L2Prive], HandlerEvenement::ConfirmerEtatFuuids),
+    evenement(DOMAINE_NOM_MAITREDESCOMPTES, EVENEMENT_SUPPRIMER_USAGER, &[Securite::L3Protege], HandlerEvenement::SupprimerUsager),
+];
+
+pub fn get_definition_action(type_action: TypeAction, domaine: &str, action: &str) -> Option<&'static DefinitionAction> {
+    REGISTRE_ACTIONS.iter().find(|d| d.type_action() == type_action && d.domaine == domaine && d.action == action)
+}
+
+/// Trouve la definition de l'action du message et verifie que le message est autorise.
+pub fn autoriser_action(m: &MessageValideAction, type_action: TypeAction) -> Result<&'static DefinitionAction, String> {
+    let definition = match get_definition_action(type_action, m.domaine.as_str(), m.action.as_str()) {
+        Some(inner) => inner,
+        None => Err(format!("registre.autoriser_action Action {:?} inconnue : {}.{}", type_action, m.domaine, m.action))?
+    };
+
+    let autorisation = &definition.autorisation;
+    if ! autorisation.roles.is_empty() && m.get_user_id().is_some() && m.verifier_roles(autorisation.roles.to_vec()) {
+        return Ok(definition)
+    }
+    if ! autorisation.exchanges.is_empty() && m.verifier_exchanges(autorisation.exchanges.to_vec()) {
+        return Ok(definition)
+    }
+    if autorisation.delegation_globale && m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        return Ok(definition)
+    }
+
+    Err(format!("registre.autoriser_action Autorisation invalide pour {:?} {} (correlation {:?})",
+                type_action, m.action, m.correlation_id))
+}
+
+pub fn nom_exchange(securite: &Securite) -> &'static str {
+    match securite {
+        Securite::L1Public => "1.public",
+        Securite::L2Prive => "2.prive",
+        Securite::L3Protege => "3.protege",
+        Securite::L4Secure => "4.secure",
+    }
+}
+
+/// Description d'une action pour la requete REQUETE_ACTIONS.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DescriptionAction {
+    pub routing_key: String,
+    pub exchanges: Vec<String>,
+}
+
+pub fn decrire_actions() -> Vec<DescriptionAction> {
+    REGISTRE_ACTIONS.iter()
+        .map(|d| DescriptionAction {
+            routing_key: d.routing_key(),
+            exchanges: d.exchanges.iter().map(|e| nom_exchange(e).to_string()).collect(),
+        })
+        .collect()
+}
@@ -1,63 +1,88 @@
+use std::collections::HashMap;
 use std::error::Error;
 use log::{debug, error};
-use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::{Bson, doc};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
-use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::openssl::rand::rand_bytes;
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
 use millegrilles_common_rust::serde_json::json;
 use millegrilles_common_rust::verificateur::VerificateurMessage;
 use millegrilles_common_rust::tokio_stream::StreamExt;
 
+use crate::registre::{autoriser_action, decrire_actions, Handler, HandlerRequete, TypeAction};
 use crate::common::*;
 use crate::constantes::*;
 use crate::gestionnaire::GestionnaireLanding;
 
 pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction, gestionnaire: &GestionnaireLanding)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
-    where M: ValidateurX509 + GenerateurMessages + MongoDao + VerificateurMessage
+    where M: Middleware + 'static
 {
     debug!("Consommer requete : {:?}", &message.message);
 
-    let user_id = message.get_user_id();
-    let role_prive = message.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    let handler = match autoriser_action(&message, TypeAction::Requete)?.handler {
+        Handler::Requete(h) => h,
+        _ => {
+            error!("Message requete/action inconnue : '{}'. Message dropped.", message.action);
+            return Ok(None)
+        }
+    };
 
-    if role_prive && user_id.is_some() {
-        // Ok, commande usager
-    } else if message.verifier_exchanges(vec![Securite::L2Prive, Securite::L3Protege]) {
-        // Autorisation : On accepte les requetes de 3.protege ou 4.secure
-        // Ok
-    } else if message.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
-        // Ok
-    } else {
-        Err(format!("consommer_requete autorisation invalide (pas d'un exchange reconnu)"))?
+    match handler {
+        HandlerRequete::GetListeApplications => requete_get_liste_applications(middleware, message, gestionnaire).await,
+        HandlerRequete::GetApplication => requete_get_application(middleware, message, gestionnaire).await,
+        HandlerRequete::GetMessages => requete_get_messages(middleware, message, gestionnaire).await,
+        HandlerRequete::GetMessage => requete_get_message(middleware, message, gestionnaire).await,
+        HandlerRequete::GetClesMessages => requete_get_cles_messages(middleware, message, gestionnaire).await,
+        HandlerRequete::GetActions => requete_get_actions(middleware, message).await,
+        HandlerRequete::GetApplicationPublique => requete_get_application_publique(middleware, message, gestionnaire).await,
+        HandlerRequete::GetVersionsApplication => requete_get_versions_application(middleware, message, gestionnaire).await,
+        HandlerRequete::GetStatistiquesApplication => requete_get_statistiques_application(middleware, message, gestionnaire).await,
+        HandlerRequete::GetDefiSoumission => requete_get_defi_soumission(middleware, message).await,
+        HandlerRequete::GetBlocages => requete_get_blocages(middleware, message, gestionnaire).await,
+        HandlerRequete::GetFichiersManquants => requete_get_fichiers_manquants(middleware, message, gestionnaire).await,
     }
+}
 
-    match message.domaine.as_str() {
-        DOMAINE_NOM => {
-            match message.action.as_str() {
-                REQUETE_LISTE_APPLICATIONS => requete_get_liste_applications(middleware, message, gestionnaire).await,
-                REQUETE_APPLICATION => requete_get_application(middleware, message, gestionnaire).await,
-                _ => {
-                    error!("Message requete/action inconnue : '{}'. Message dropped.", message.action);
-                    Ok(None)
-                },
-            }
-        },
-        _ => {
-            error!("Message requete/domaine inconnu : '{}'. Message dropped.", message.domaine);
-            Ok(None)
-        },
-    }
+async fn requete_get_actions<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    debug!("requete_get_actions Message : {:?}", & m.message);
+    let reponse = json!({ "actions": decrire_actions() });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TriApplications {
+    Nom,
+    Creation,
+    Modification,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct RequeteGetListeApplications {
     limit: Option<i32>,
     skip: Option<i32>,
+    tri: Option<TriApplications>,
+    /// true pour un tri decroissant
+    tri_inverse: Option<bool>,
+    actif: Option<bool>,
+    /// Recherche (insensible a la casse) dans le nom de l'application
+    recherche_nom: Option<String>,
+    /// true pour lister les applications dans la corbeille
+    supprimees: Option<bool>,
+    /// true pour inclure les applications archivees
+    archivees: Option<bool>,
 }
 
 async fn requete_get_liste_applications<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
@@ -73,36 +98,81 @@ async fn requete_get_liste_applications<M>(middleware: &M, m: MessageValideActio
     };
 
     let limit = match requete.limit {
-        Some(l) => l,
+        Some(l) => l.max(1).min(1000),
         None => 100
     };
     let skip = match requete.skip {
-        Some(s) => s,
+        Some(s) => s.max(0),
         None => 0
     };
 
-    let applications = {
-        let mut applications = Vec::new();
+    let mut filtre = doc! { "user_id": &user_id };
+    match requete.supprimees {
+        Some(true) => filtre.insert(CHAMP_SUPPRIME, true),
+        _ => filtre.insert(CHAMP_SUPPRIME, doc! {"$ne": true}),
+    };
+    if requete.archivees != Some(true) {
+        filtre.insert(CHAMP_ARCHIVE, doc! {"$ne": true});
+    }
+    if let Some(actif) = requete.actif {
+        filtre.insert(CHAMP_ACTIF, actif);
+    }
+    if let Some(recherche) = requete.recherche_nom.as_ref() {
+        if ! recherche.is_empty() {
+            filtre.insert("nom", doc! {"$regex": echapper_regex(recherche), "$options": "i"});
+        }
+    }
+
+    let direction = match requete.tri_inverse {
+        Some(true) => -1,
+        _ => 1
+    };
+    let champ_tri = match requete.tri.as_ref() {
+        Some(TriApplications::Nom) => "nom",
+        Some(TriApplications::Creation) => CHAMP_CREATION,
+        Some(TriApplications::Modification) => CHAMP_MODIFICATION,
+        None => CHAMP_CREATION,
+    };
+    let options = FindOptions::builder()
+        .sort(doc! { champ_tri: direction, CHAMP_APPLICATION_ID: direction })
+        .skip(skip as u64)
+        .limit(limit as i64)
+        .build();
 
-        let filtre = doc! { "user_id": &user_id };
-        let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let total = collection.count_documents(filtre.clone(), None).await?;
 
-        let mut curseur = collection.find(filtre, None).await?;
+    let applications = {
+        let mut applications = Vec::new();
+        let mut curseur = collection.find(filtre, options).await?;
         while let Some(doc_app) = curseur.next().await {
             let app: DocApplication = convertir_bson_deserializable(doc_app?)?;
             applications.push(app);
         }
-
         applications
     };
 
-    let reponse = json!({ "applications": applications });
+    let reponse = json!({ "applications": applications, "total": total, "skip": skip, "limit": limit });
     Ok(Some(middleware.formatter_reponse(&reponse, None)?))
 }
 
+/// Echappe les caracteres speciaux d'une expression reguliere (recherche texte litterale).
+fn echapper_regex(valeur: &str) -> String {
+    let mut resultat = String::with_capacity(valeur.len());
+    for c in valeur.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            resultat.push('\\');
+        }
+        resultat.push(c);
+    }
+    resultat
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct RequeteGetApplication {
     application_id: String,
+    /// Adresse du visiteur ajoutee par un relais, voir calculer_hachage_source
+    adresse_source: Option<String>,
 }
 
 async fn requete_get_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
@@ -130,6 +200,554 @@ async fn requete_get_application<M>(middleware: &M, m: MessageValideAction, gest
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReponseApplicationPublique {
+    application: ApplicationPublique,
+    /// Certificats (chaine PEM) de maitre des cles pour chiffrer les messages
+    certificats_chiffrage: Vec<Vec<String>>,
+}
+
+async fn requete_get_application_publique<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: Middleware
+{
+    debug!("requete_get_application_publique Message : {:?}", & m.message);
+    let requete: RequeteGetApplication = m.message.get_msg().map_contenu(None)?;
+    let source = get_source_soumission(&m, requete.adresse_source.as_ref());
+
+    let filtre = doc! { CHAMP_APPLICATION_ID: &requete.application_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application: ApplicationPublique = match collection.find_one(filtre, None).await? {
+        Some(d) => {
+            let app: DocApplication = convertir_bson_deserializable(d)?;
+            // Application dans la corbeille ou archivee : le formulaire n'est plus servi
+            if app.supprime == Some(true) || app.archive == Some(true) {
+                let reponse = json!({"ok": false, "err": "Application non disponible", "code": "indisponible"});
+                return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+            }
+            let places_restantes = charger_places_restantes(middleware, &app, &Utc::now()).await?;
+            let delai_spam = app.configuration_spam.as_ref().and_then(|c| c.delai_minimum_secondes);
+            let application_id = app.application_id.clone();
+            let mut application: ApplicationPublique = app.into();
+            application.places_restantes = places_restantes;
+            if delai_spam.is_some() && verifier_debit_jetons(gestionnaire, source.hachage.as_str()).await {
+                application.jeton_formulaire = Some(emettre_jeton_formulaire(middleware, &application_id).await?);
+            }
+            if places_restantes == Some(0) {
+                application.ouvert = false;
+            }
+            application
+        },
+        None => return Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Application inconnue"}), None)?))
+    };
+
+    // Certificats de maitre des cles charges par l'entretien. Charger au besoin (demarrage).
+    let mut certificats_chiffrage = gestionnaire.get_certificats_chiffrage();
+    if certificats_chiffrage.is_empty() {
+        gestionnaire.charger_certificats_chiffrage(middleware).await?;
+        certificats_chiffrage = gestionnaire.get_certificats_chiffrage();
+    }
+
+    let reponse = ReponseApplicationPublique { application, certificats_chiffrage };
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetVersionsApplication {
+    application_id: String,
+    /// Version specifique (contenu complet). Sans version, retourne la liste des versions.
+    version: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SommaireVersion {
+    version: i64,
+    date_publication: i64,
+    version_source: Option<i64>,
+}
+
+async fn requete_get_versions_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_versions_application Message : {:?}", & m.message);
+    let requete: RequeteGetVersionsApplication = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let mut filtre = doc! { CHAMP_APPLICATION_ID: &requete.application_id, CHAMP_USER_ID: &user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_VERSIONS)?;
+
+    if let Some(version) = requete.version {
+        filtre.insert(CHAMP_VERSION, version);
+        return match collection.find_one(filtre, None).await? {
+            Some(d) => {
+                let doc_version: DocVersionApplication = convertir_bson_deserializable(d)?;
+                Ok(Some(middleware.formatter_reponse(&doc_version, None)?))
+            },
+            None => Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Version inconnue"}), None)?))
+        }
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { CHAMP_VERSION: -1 })
+        .projection(doc! { CHAMP_VERSION: 1, "date_publication": 1, "version_source": 1 })
+        .build();
+    let mut versions = Vec::new();
+    let mut curseur = collection.find(filtre, options).await?;
+    while let Some(d) = curseur.next().await {
+        let sommaire: SommaireVersion = convertir_bson_deserializable(d?)?;
+        versions.push(sommaire);
+    }
+
+    let reponse = json!({ "versions": versions });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReponseStatistiquesApplication {
+    application_id: String,
+    /// Nombre de soumissions acceptees par periode (cle "total", "jour/...", "semaine/...", "mois/...")
+    soumissions: HashMap<String, i64>,
+    /// Nombre de soumissions rejetees par raison
+    rejets: HashMap<String, i64>,
+}
+
+async fn requete_get_statistiques_application<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_statistiques_application Message : {:?}", & m.message);
+    let requete: RequeteGetApplication = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let filtre = doc! { CHAMP_APPLICATION_ID: &requete.application_id, CHAMP_USER_ID: &user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    if collection.count_documents(filtre, None).await? == 0 {
+        return Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Application inconnue"}), None)?))
+    }
+
+    // Compteurs de la periode courante
+    let maintenant = Utc::now();
+    let periodes: Vec<String> = [None, Some(PeriodeLimite::Jour), Some(PeriodeLimite::Semaine), Some(PeriodeLimite::Mois)].iter()
+        .map(|p| cle_periode(p.as_ref(), &maintenant))
+        .collect();
+    let filtre = doc! { CHAMP_APPLICATION_ID: &requete.application_id, CHAMP_PERIODE: {"$in": &periodes} };
+    let collection = middleware.get_collection(NOM_COLLECTION_COMPTEURS)?;
+    let mut soumissions = HashMap::new();
+    let mut curseur = collection.find(filtre, None).await?;
+    while let Some(d) = curseur.next().await {
+        let compteur: DocCompteurSoumissions = convertir_bson_deserializable(d?)?;
+        soumissions.insert(compteur.periode, compteur.nombre);
+    }
+
+    let filtre = doc! { CHAMP_APPLICATION_ID: &requete.application_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_STATISTIQUES)?;
+    let rejets = match collection.find_one(filtre, None).await? {
+        Some(d) => {
+            let statistiques: DocStatistiquesApplication = convertir_bson_deserializable(d)?;
+            statistiques.rejets.unwrap_or_default()
+        },
+        None => HashMap::new()
+    };
+
+    let reponse = ReponseStatistiquesApplication { application_id: requete.application_id, soumissions, rejets };
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetBlocages {
+    /// Absent pour la liste globale (delegation globale requise)
+    application_id: Option<String>,
+}
+
+async fn requete_get_blocages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_blocages Message : {:?}", & m.message);
+    let requete: RequeteGetBlocages = m.message.get_msg().map_contenu(None)?;
+
+    let filtre = match requete.application_id.as_ref() {
+        Some(application_id) => match m.get_user_id() {
+            Some(u) => doc! { CHAMP_APPLICATION_ID: application_id, CHAMP_USER_ID: u },
+            None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+        },
+        None => match m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+            true => doc! { CHAMP_APPLICATION_ID: None::<&str> },
+            false => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+        }
+    };
+
+    let options = FindOptions::builder()
+        .sort(doc! { "date_blocage": -1 })
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_BLOCAGES)?;
+    let mut blocages = Vec::new();
+    let mut curseur = collection.find(filtre, options).await?;
+    while let Some(d) = curseur.next().await {
+        let blocage: DocBlocage = convertir_bson_deserializable(d?)?;
+        blocages.push(blocage);
+    }
+
+    let reponse = json!({ "blocages": blocages });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetFichiersManquants {
+    application_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MessageFichiersManquants {
+    message_id: String,
+    application_id: String,
+    date_reception: i64,
+    fichiers_manquants: Vec<String>,
+}
+
+/// Liste les messages de l'usager dont des fichiers joints ne sont pas encore consignes.
+async fn requete_get_fichiers_manquants<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_fichiers_manquants Message : {:?}", & m.message);
+    let requete: RequeteGetFichiersManquants = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id, "fichiers_manquants.0": {"$exists": true} };
+    if let Some(application_id) = requete.application_id.as_ref() {
+        filtre.insert(CHAMP_APPLICATION_ID, application_id);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { CHAMP_DATE_RECEPTION: -1 })
+        .projection(doc! { CHAMP_MESSAGE_ID: 1, CHAMP_APPLICATION_ID: 1, CHAMP_DATE_RECEPTION: 1, CHAMP_FICHIERS_MANQUANTS: 1 })
+        .limit(1000)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let mut messages = Vec::new();
+    let mut curseur = collection.find(filtre, options).await?;
+    while let Some(d) = curseur.next().await {
+        let message: MessageFichiersManquants = convertir_bson_deserializable(d?)?;
+        messages.push(message);
+    }
+
+    let reponse = json!({ "messages": messages });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Limite l'emission de jetons de formulaire (une insertion par chargement du formulaire public).
+/// Au-dela de la limite, le formulaire est retourne sans jeton. La source est le hachage de l'emetteur
+/// (SourceSoumission.hachage).
+async fn verifier_debit_jetons(gestionnaire: &GestionnaireLanding, source: &str) -> bool {
+    let limiteur = gestionnaire.limiteur_debit.as_ref();
+    let cle = format!("jetons:{}", source);
+    if ! limiteur.verifier(cle.as_str(), &limiteur.limite_jetons_source).await {
+        debug!("verifier_debit_jetons Limite source {} atteinte", source);
+        return false
+    }
+    if limiteur.reserver("jetons", &limiteur.limite_jetons_globale).await.is_none() {
+        debug!("verifier_debit_jetons Limite globale atteinte");
+        return false
+    }
+    true
+}
+
+/// Conserve un jeton de formulaire pour mesurer le delai avant la soumission (detection de spam).
+async fn emettre_jeton_formulaire<M>(middleware: &M, application_id: &str) -> Result<JetonFormulaire, Box<dyn Error>>
+    where M: MongoDao
+{
+    let mut octets = [0u8; 16];
+    rand_bytes(&mut octets)?;
+    let jeton_id: String = octets.iter().map(|o| format!("{:02x}", o)).collect();
+    let date_creation = Utc::now().timestamp();
+
+    let doc_jeton = DocJetonFormulaire {
+        jeton_id: jeton_id.clone(),
+        application_id: application_id.to_owned(),
+        date_creation,
+        expiration: date_creation + JETON_DUREE_VALIDITE_SECONDES,
+        utilise: false,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_JETONS)?;
+    collection.insert_one(convertir_to_bson(&doc_jeton)?, None).await?;
+
+    Ok(JetonFormulaire { jeton_id, date_creation })
+}
+
+/// Emet un defi de preuve de travail pour une soumission a une application. La reponse est signee
+/// par le domaine, le defi est conserve pour verification et protection contre le rejeu.
+async fn requete_get_defi_soumission<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_defi_soumission Message : {:?}", & m.message);
+    let requete: RequeteGetApplication = m.message.get_msg().map_contenu(None)?;
+
+    let filtre = doc! {
+        CHAMP_APPLICATION_ID: &requete.application_id,
+        CHAMP_SUPPRIME: {"$ne": true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application: DocApplication = match collection.find_one(filtre, None).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => return Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Application inconnue"}), None)?))
+    };
+
+    let difficulte = match application.difficulte_defi {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Aucun defi requis"}), None)?))
+    };
+
+    let mut octets = [0u8; 16];
+    rand_bytes(&mut octets)?;
+    let defi_id: String = octets.iter().map(|o| format!("{:02x}", o)).collect();
+
+    let defi = DocDefiSoumission {
+        defi_id,
+        application_id: application.application_id,
+        difficulte,
+        expiration: Utc::now().timestamp() + DEFI_DUREE_VALIDITE_SECONDES,
+        utilise: false,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_DEFIS)?;
+    collection.insert_one(convertir_to_bson(&defi)?, None).await?;
+
+    let reponse = json!({
+        "ok": true,
+        "defi_id": &defi.defi_id,
+        "difficulte": defi.difficulte,
+        "expiration": defi.expiration,
+    });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Position dans la liste de messages (tri date_reception, message_id decroissant).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CurseurMessages {
+    date_reception: i64,
+    message_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetMessages {
+    application_id: Option<String>,
+    lu: Option<bool>,
+    date_debut: Option<i64>,
+    date_fin: Option<i64>,
+    /// true pour lister les messages en quarantaine (spam) plutot que la boite de reception
+    quarantaine: Option<bool>,
+    etats: Option<Vec<EtatMessage>>,
+    /// Messages qui ont toutes ces etiquettes
+    etiquettes: Option<Vec<String>>,
+    assigne: Option<String>,
+    /// true pour lister les messages sans assignation
+    non_assigne: Option<bool>,
+    curseur: Option<CurseurMessages>,
+    limit: Option<i64>,
+}
+
+async fn requete_get_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_messages Message : {:?}", & m.message);
+    let requete: RequeteGetMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let limit = match requete.limit {
+        Some(l) => l.max(1).min(1000),
+        None => 100
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id };
+    if let Some(application_id) = requete.application_id.as_ref() {
+        filtre.insert(CHAMP_APPLICATION_ID, application_id);
+    }
+    if let Some(lu) = requete.lu {
+        filtre.insert(CHAMP_LU, lu);
+    }
+    match requete.quarantaine {
+        Some(true) => filtre.insert(CHAMP_QUARANTAINE, true),
+        _ => filtre.insert(CHAMP_QUARANTAINE, doc! {"$ne": true}),
+    };
+    if let Some(etats) = requete.etats.as_ref() {
+        filtre.insert(CHAMP_ETAT, filtre_etats_messages(etats));
+    }
+    if let Some(etiquettes) = requete.etiquettes.as_ref() {
+        filtre.insert(CHAMP_ETIQUETTES, doc! {"$all": etiquettes});
+    }
+    if let Some(assigne) = requete.assigne.as_ref() {
+        filtre.insert(CHAMP_ASSIGNE, assigne);
+    } else if requete.non_assigne == Some(true) {
+        filtre.insert(CHAMP_ASSIGNE, Bson::Null);
+    }
+    if requete.date_debut.is_some() || requete.date_fin.is_some() {
+        let mut filtre_date = doc! {};
+        if let Some(d) = requete.date_debut { filtre_date.insert("$gte", d); }
+        if let Some(d) = requete.date_fin { filtre_date.insert("$lte", d); }
+        filtre.insert(CHAMP_DATE_RECEPTION, filtre_date);
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+
+    // Le total ignore le curseur (nombre de messages pour les filtres)
+    let total = collection.count_documents(filtre.clone(), None).await?;
+
+    if let Some(curseur) = requete.curseur.as_ref() {
+        filtre.insert("$or", vec![
+            doc! { CHAMP_DATE_RECEPTION: {"$lt": curseur.date_reception} },
+            doc! { CHAMP_DATE_RECEPTION: curseur.date_reception, CHAMP_MESSAGE_ID: {"$lt": &curseur.message_id} },
+        ]);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { CHAMP_DATE_RECEPTION: -1, CHAMP_MESSAGE_ID: -1 })
+        .limit(limit)
+        .build();
+
+    let mut messages = Vec::new();
+    let mut curseur_db = collection.find(filtre, options).await?;
+    while let Some(doc_message) = curseur_db.next().await {
+        let message: DocMessage = convertir_bson_deserializable(doc_message?)?;
+        messages.push(message);
+    }
+
+    // Curseur pour la prochaine page, absent si la liste est terminee
+    let curseur_suivant = match messages.last() {
+        Some(dernier) if messages.len() as i64 == limit => Some(CurseurMessages {
+            date_reception: dernier.date_reception,
+            message_id: dernier.message_id.clone(),
+        }),
+        _ => None
+    };
+
+    let reponse = json!({ "messages": messages, "total": total, "curseur": curseur_suivant });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetMessage {
+    message_id: String,
+}
+
+async fn requete_get_message<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_message Message : {:?}", & m.message);
+    let requete: RequeteGetMessage = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let filtre = doc! { CHAMP_MESSAGE_ID: &requete.message_id, CHAMP_USER_ID: &user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    match collection.find_one(filtre, None).await? {
+        Some(d) => {
+            let message: DocMessage = convertir_bson_deserializable(d)?;
+            Ok(Some(middleware.formatter_reponse(&message, None)?))
+        },
+        None => Ok(Some(middleware.formatter_reponse(&json!({"ok": false, "err": "Message inconnu"}), None)?))
+    }
+}
+
+const LIMITE_CLES_MESSAGES: usize = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RequeteGetClesMessages {
+    message_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MessageHachageBytesMapping {
+    message_id: String,
+    ref_hachage_bytes: String,
+}
+
+async fn requete_get_cles_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireLanding)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_cles_messages Message : {:?}", & m.message);
+    let requete: RequeteGetClesMessages = m.message.get_msg().map_contenu(None)?;
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let mut message_ids = requete.message_ids;
+    message_ids.sort();
+    message_ids.dedup();
+    if message_ids.is_empty() || message_ids.len() > LIMITE_CLES_MESSAGES {
+        let reponse = json!({"ok": false, "err": format!("Nombre de messages invalide (1 a {})", LIMITE_CLES_MESSAGES)});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
+    }
+
+    let certificat_client: Vec<String> = match m.message.certificat.as_ref() {
+        Some(c) => c.get_pem_vec().iter().map(|c| c.pem.to_owned()).collect(),
+        None => Err(format!("requetes.requete_get_cles_messages Certificat manquant"))?
+    };
+
+    let filtre = doc! {
+        CHAMP_USER_ID: &user_id,
+        CHAMP_MESSAGE_ID: {"$in": &message_ids},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let mut curseur = collection.find(filtre, None).await?;
+
+    let mut liste_hachage_bytes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        let valeur: MessageHachageBytesMapping = convertir_bson_deserializable(row?)?;
+        liste_hachage_bytes.push(valeur.ref_hachage_bytes);
+    }
+
+    // Tous les messages doivent appartenir a l'usager
+    if liste_hachage_bytes.len() != message_ids.len() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    }
+
+    let (reply_q, correlation_id) = match (m.reply_q.as_ref(), m.correlation_id.as_ref()) {
+        (Some(r), Some(c)) => (r.to_owned(), c.to_owned()),
+        _ => Err(format!("requetes.requete_get_cles_messages reply_q/correlation_id manquant"))?
+    };
+
+    // Creer nouvelle requete pour MaitreDesCles, rediriger vers client
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE)
+        .exchanges(vec![Securite::L4Secure])
+        .reply_to(reply_q)
+        .correlation_id(correlation_id)
+        .blocking(false)
+        .build();
+    let requete_cles = json!({
+        "liste_hachage_bytes": liste_hachage_bytes,
+        "certificat_rechiffrage": certificat_client,
+    });
+    middleware.transmettre_requete(routage, &requete_cles).await?;
+
+    Ok(None)
+}
+
 // #[derive(Clone, Debug, Serialize, Deserialize)]
 // struct RequeteGetGroupesUsager {
 //     limit: Option<i32>,
@@ -0,0 +1,287 @@
+use millegrilles_common_rust::openssl::sha::sha256;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+use crate::common::TransactionSoumettreMessage;
+use crate::constantes::*;
+
+/// Configuration de la detection de spam d'une application. Un critere sans configuration est ignore.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConfigurationSpam {
+    /// Noms de champs caches du formulaire. Un visiteur ne les remplit pas, un robot oui.
+    pub champs_pieges: Option<Vec<String>>,
+    /// Delai minimal entre le chargement du formulaire (jeton) et la soumission
+    pub delai_minimum_secondes: Option<i64>,
+    /// Detecter les soumissions identiques recues recemment
+    pub detecter_doublons: Option<bool>,
+    /// Mots (insensible a la casse) recherches dans les champs non chiffres
+    pub mots_cles: Option<Vec<String>>,
+    /// Pointage a partir duquel le message est mis en quarantaine (defaut SEUIL_SPAM_DEFAUT)
+    pub seuil: Option<i32>,
+}
+
+impl ConfigurationSpam {
+    pub fn valider(&self) -> Result<(), String> {
+        if let Some(d) = self.delai_minimum_secondes {
+            if d < 0 || d > DELAI_MINIMUM_SPAM_MAXIMUM_SECONDES { Err(format!("delai_minimum_secondes invalide"))? }
+        }
+        if let Some(s) = self.seuil {
+            if s < 1 { Err(format!("seuil doit etre plus grand que 0"))? }
+        }
+        if let Some(mots) = self.mots_cles.as_ref() {
+            if mots.len() > NOMBRE_MAXIMUM_MOTS_CLES_SPAM { Err(format!("trop de mots_cles"))? }
+        }
+        if let Some(champs) = self.champs_pieges.as_ref() {
+            if champs.len() > NOMBRE_MAXIMUM_CHAMPS_PIEGES { Err(format!("trop de champs_pieges"))? }
+        }
+        Ok(())
+    }
+}
+
+/// Information sur la soumission disponible lors de l'evaluation. Toutes les valeurs proviennent de
+/// la transaction ou de l'etat regenere pour que le resultat soit le meme lors d'une regeneration.
+pub struct ContexteSpam<'a> {
+    pub transaction: &'a TransactionSoumettreMessage,
+    /// Secondes entre l'emission du jeton de formulaire et la reception (None si aucun jeton)
+    pub delai_secondes: Option<i64>,
+    /// Une soumission identique a ete recue recemment
+    pub doublon: bool,
+}
+
+/// Critere de detection. Ajouter un critere ici et dans CRITERES_SPAM.
+#[derive(Clone, Copy, Debug)]
+pub enum CritereSpam {
+    ChampsPieges,
+    DelaiMinimum,
+    Doublon,
+    MotsCles,
+}
+
+pub const CRITERES_SPAM: &[CritereSpam] = &[
+    CritereSpam::ChampsPieges,
+    CritereSpam::DelaiMinimum,
+    CritereSpam::Doublon,
+    CritereSpam::MotsCles,
+];
+
+impl CritereSpam {
+    /// Retourne le pointage et la raison lorsque le critere detecte la soumission.
+    fn evaluer(&self, config: &ConfigurationSpam, contexte: &ContexteSpam) -> Option<(i32, String)> {
+        match self {
+            CritereSpam::ChampsPieges => {
+                let champs = config.champs_pieges.as_ref()?;
+                let pieges = contexte.transaction.pieges.as_ref()?;
+                let rempli = champs.iter()
+                    .any(|c| pieges.get(c).map(|v| ! v.trim().is_empty()).unwrap_or(false));
+                match rempli {
+                    true => Some((POINTAGE_SPAM_PIEGE, String::from("piege"))),
+                    false => None
+                }
+            },
+            CritereSpam::DelaiMinimum => {
+                let minimum = config.delai_minimum_secondes?;
+                match contexte.delai_secondes {
+                    Some(d) if d >= minimum => None,
+                    _ => Some((POINTAGE_SPAM_DELAI, String::from("delai")))
+                }
+            },
+            CritereSpam::Doublon => match config.detecter_doublons == Some(true) && contexte.doublon {
+                true => Some((POINTAGE_SPAM_DOUBLON, String::from("doublon"))),
+                false => None
+            },
+            CritereSpam::MotsCles => {
+                let mots = config.mots_cles.as_ref()?;
+                let enveloppe = contexte.transaction.enveloppe.as_ref()?;
+                let valeurs: Vec<String> = enveloppe.champs.iter()
+                    .filter_map(|c| c.valeur.as_ref().map(|v| v.to_lowercase()))
+                    .collect();
+                let trouves = mots.iter()
+                    .filter(|m| ! m.trim().is_empty())
+                    .filter(|m| { let m = m.to_lowercase(); valeurs.iter().any(|v| v.contains(m.as_str())) })
+                    .count() as i32;
+                match trouves {
+                    0 => None,
+                    n => Some((n * POINTAGE_SPAM_MOT_CLE, String::from("mots_cles")))
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ResultatSpam {
+    pub pointage: i32,
+    pub raisons: Vec<String>,
+    pub quarantaine: bool,
+}
+
+pub fn evaluer_spam(config: &ConfigurationSpam, contexte: &ContexteSpam) -> ResultatSpam {
+    let mut resultat = ResultatSpam::default();
+    for critere in CRITERES_SPAM {
+        if let Some((pointage, raison)) = critere.evaluer(config, contexte) {
+            resultat.pointage += pointage;
+            resultat.raisons.push(raison);
+        }
+    }
+    resultat.quarantaine = resultat.pointage >= config.seuil.unwrap_or(SEUIL_SPAM_DEFAUT);
+    resultat
+}
+
+/// Hachage du contenu d'une soumission pour detecter les doublons. Utilise les champs non chiffres
+/// de l'enveloppe lorsque presents (le contenu chiffre change a chaque soumission).
+pub fn hacher_contenu(transaction: &TransactionSoumettreMessage) -> String {
+    let valeurs: Vec<String> = match transaction.enveloppe.as_ref() {
+        Some(e) if e.champs.iter().any(|c| c.valeur.is_some()) => e.champs.iter()
+            .map(|c| format!("{}={}", c.code_interne, c.valeur.as_ref().map(|v| v.as_str()).unwrap_or("")))
+            .collect(),
+        _ => vec![transaction.data_chiffre.clone()]
+    };
+    sha256(valeurs.join("\n").as_bytes()).iter().map(|o| format!("{:02x}", o)).collect()
+}
+
+#[cfg(test)]
+mod test_spam {
+    use std::collections::HashMap;
+    use millegrilles_common_rust::serde_json::{from_value, json};
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn transaction(pieges: Option<HashMap<String, String>>, valeurs: Vec<(&str, &str)>) -> TransactionSoumettreMessage {
+        let champs: Vec<_> = valeurs.into_iter()
+            .map(|(code, valeur)| json!({"code_interne": code, "taille": valeur.len(), "valeur": valeur}))
+            .collect();
+        let mut transaction: TransactionSoumettreMessage = from_value(json!({
+            "application_id": "app",
+            "data_chiffre": "mABCD",
+            "format": "mgs4",
+            "header": "mHEADER",
+            "ref_hachage_bytes": "zREF",
+            "enveloppe": {"taille": 100, "champs": champs},
+        })).expect("transaction");
+        transaction.pieges = pieges;
+        transaction
+    }
+
+    fn contexte(transaction: &TransactionSoumettreMessage, delai_secondes: Option<i64>, doublon: bool) -> ContexteSpam {
+        ContexteSpam { transaction, delai_secondes, doublon }
+    }
+
+    #[test]
+    fn test_sans_configuration() {
+        setup("test_sans_configuration");
+        let t = transaction(None, vec![("nom", "viagra")]);
+        let resultat = evaluer_spam(&ConfigurationSpam::default(), &contexte(&t, None, true));
+        assert_eq!(0, resultat.pointage);
+        assert!(resultat.raisons.is_empty());
+        assert!(! resultat.quarantaine);
+    }
+
+    #[test]
+    fn test_champs_pieges() {
+        setup("test_champs_pieges");
+        let config = ConfigurationSpam { champs_pieges: Some(vec![String::from("site_web")]), ..Default::default() };
+
+        let pieges = HashMap::from([(String::from("site_web"), String::from("http://spam"))]);
+        let t = transaction(Some(pieges), vec![]);
+        let resultat = evaluer_spam(&config, &contexte(&t, None, false));
+        assert_eq!(POINTAGE_SPAM_PIEGE, resultat.pointage);
+        assert_eq!(vec!["piege"], resultat.raisons);
+        assert!(resultat.quarantaine);
+
+        // Piege vide ou espaces seulement
+        let pieges = HashMap::from([(String::from("site_web"), String::from("  "))]);
+        let t = transaction(Some(pieges), vec![]);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+
+        // Champ rempli qui n'est pas un piege configure
+        let pieges = HashMap::from([(String::from("autre"), String::from("valeur"))]);
+        let t = transaction(Some(pieges), vec![]);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+
+        let t = transaction(None, vec![]);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+    }
+
+    #[test]
+    fn test_delai_minimum() {
+        setup("test_delai_minimum");
+        let config = ConfigurationSpam { delai_minimum_secondes: Some(5), ..Default::default() };
+        let t = transaction(None, vec![]);
+
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, Some(5), false)).pointage);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, Some(60), false)).pointage);
+
+        let resultat = evaluer_spam(&config, &contexte(&t, Some(4), false));
+        assert_eq!(POINTAGE_SPAM_DELAI, resultat.pointage);
+        assert_eq!(vec!["delai"], resultat.raisons);
+
+        // Aucun jeton de formulaire
+        assert_eq!(POINTAGE_SPAM_DELAI, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+    }
+
+    #[test]
+    fn test_doublon() {
+        setup("test_doublon");
+        let config = ConfigurationSpam { detecter_doublons: Some(true), ..Default::default() };
+        let t = transaction(None, vec![]);
+
+        let resultat = evaluer_spam(&config, &contexte(&t, None, true));
+        assert_eq!(POINTAGE_SPAM_DOUBLON, resultat.pointage);
+        assert_eq!(vec!["doublon"], resultat.raisons);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+
+        let config = ConfigurationSpam { detecter_doublons: Some(false), ..Default::default() };
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, true)).pointage);
+    }
+
+    #[test]
+    fn test_mots_cles() {
+        setup("test_mots_cles");
+        let mots = vec![String::from("Casino"), String::from("crypto"), String::from(" "), String::from("absent")];
+        let config = ConfigurationSpam { mots_cles: Some(mots), ..Default::default() };
+
+        // Insensible a la casse, un pointage par mot trouve, mot vide ignore
+        let t = transaction(None, vec![("sujet", "CASINO gratuit"), ("choix", "Crypto")]);
+        let resultat = evaluer_spam(&config, &contexte(&t, None, false));
+        assert_eq!(2 * POINTAGE_SPAM_MOT_CLE, resultat.pointage);
+        assert_eq!(vec!["mots_cles"], resultat.raisons);
+
+        let t = transaction(None, vec![("sujet", "bonjour")]);
+        assert_eq!(0, evaluer_spam(&config, &contexte(&t, None, false)).pointage);
+    }
+
+    #[test]
+    fn test_seuil() {
+        setup("test_seuil");
+        let mots = vec![String::from("casino"), String::from("crypto"), String::from("pilule")];
+        let t1 = transaction(None, vec![("sujet", "casino")]);
+        let t2 = transaction(None, vec![("sujet", "casino crypto")]);
+        let t3 = transaction(None, vec![("sujet", "casino crypto pilule")]);
+
+        // Seuil par defaut
+        let config = ConfigurationSpam { mots_cles: Some(mots.clone()), ..Default::default() };
+        assert!(POINTAGE_SPAM_MOT_CLE < SEUIL_SPAM_DEFAUT);
+        assert!(! evaluer_spam(&config, &contexte(&t1, None, false)).quarantaine);
+        assert!(evaluer_spam(&config, &contexte(&t2, None, false)).quarantaine);
+
+        // Seuil configure, la quarantaine commence au seuil
+        let config = ConfigurationSpam { mots_cles: Some(mots), seuil: Some(75), ..Default::default() };
+        assert!(! evaluer_spam(&config, &contexte(&t2, None, false)).quarantaine);
+        assert!(evaluer_spam(&config, &contexte(&t3, None, false)).quarantaine);
+    }
+
+    #[test]
+    fn test_criteres_cumules() {
+        setup("test_criteres_cumules");
+        let config = ConfigurationSpam {
+            delai_minimum_secondes: Some(5),
+            detecter_doublons: Some(true),
+            mots_cles: Some(vec![String::from("casino")]),
+            ..Default::default()
+        };
+        let t = transaction(None, vec![("sujet", "casino")]);
+        let resultat = evaluer_spam(&config, &contexte(&t, Some(1), true));
+        assert_eq!(POINTAGE_SPAM_DELAI + POINTAGE_SPAM_DOUBLON + POINTAGE_SPAM_MOT_CLE, resultat.pointage);
+        assert_eq!(vec!["delai", "doublon", "mots_cles"], resultat.raisons);
+        assert!(resultat.quarantaine);
+    }
+}
@@ -1,22 +1,29 @@
+use std::collections::HashMap;
 use std::error::Error;
-use log::{debug, error};
-use millegrilles_common_rust::bson::{Bson, doc};
+use log::{debug, error, info};
+use millegrilles_common_rust::bson::{Bson, doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::chrono::{DateTime, TimeZone, Utc};
 use millegrilles_common_rust::common_messages::verifier_reponse_ok;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction;
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, convertir_to_bson_array, MongoDao};
+use millegrilles_common_rust::mongodb::error::{ErrorKind, WriteFailure};
 use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
 use millegrilles_common_rust::serde_json::json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::transactions::Transaction;
 
+use crate::registre::{autoriser_action, get_definition_action, Handler, HandlerTransaction, TypeAction};
 use crate::common::*;
 use crate::constantes::*;
-use crate::gestionnaire::GestionnaireLanding;
+use crate::evenements::{emettre_evenement_application_maj, emettre_evenement_nouveau_message};
+use crate::gestionnaire::{GestionnaireLanding, liberer_fuuids};
+use crate::messagerie::ajouter_transfert;
+use crate::spam::{ContexteSpam, evaluer_spam, hacher_contenu};
 
 pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
     -> Result<Option<MessageMilleGrille>, String>
@@ -24,10 +31,41 @@ pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireLanding, mi
         M: ValidateurX509 + GenerateurMessages + MongoDao,
         T: Transaction
 {
-    match transaction.get_action() {
-        TRANSACTION_CREER_NOUVELLE_APPLICATION => transaction_creer_nouvelle_application(gestionnaire, middleware, transaction).await,
-        TRANSACTION_SAUVEGARDER_APPLICATION => transaction_sauvegarder_application(gestionnaire, middleware, transaction).await,
-        _ => Err(format!("transactions.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.get_uuid_transaction(), transaction.get_action())),
+    let handler = match get_definition_action(TypeAction::Transaction, DOMAINE_NOM, transaction.get_action()) {
+        Some(d) => match d.handler {
+            Handler::Transaction(h) => h,
+            _ => Err(format!("transactions.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.get_uuid_transaction(), transaction.get_action()))?
+        },
+        None => Err(format!("transactions.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.get_uuid_transaction(), transaction.get_action()))?
+    };
+
+    match handler {
+        HandlerTransaction::AppliquerHoraireApplication => transaction_appliquer_horaire_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::PurgerApplications => transaction_purger_applications(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SupprimerApplicationsUsager => transaction_supprimer_applications_usager(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::CreerNouvelleApplication => transaction_creer_nouvelle_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderApplication => transaction_sauvegarder_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderPageApplication => transaction_sauvegarder_page_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SupprimerApplication => transaction_supprimer_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::ArchiverApplication => transaction_archiver_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::RestaurerApplication => transaction_restaurer_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::PublierApplication => transaction_publier_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::RestaurerVersionApplication => transaction_restaurer_version_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderHoraireApplication => transaction_sauvegarder_horaire_application(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderLimiteSoumissions => transaction_sauvegarder_limite_soumissions(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderLimiteDebit => transaction_sauvegarder_limite_debit(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderDifficulteDefi => transaction_sauvegarder_difficulte_defi(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderConfigurationSpam => transaction_sauvegarder_configuration_spam(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::LibererMessages => transaction_liberer_messages(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SupprimerMessages => transaction_supprimer_messages(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::ChangerEtatMessages => transaction_changer_etat_messages(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::EtiqueterMessages => transaction_etiqueter_messages(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::AssignerMessages => transaction_assigner_messages(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::BloquerSource => transaction_bloquer_source(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::DebloquerSource => transaction_debloquer_source(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderLimitesFichiers => transaction_sauvegarder_limites_fichiers(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SauvegarderTransfertMessagerie => transaction_sauvegarder_transfert_messagerie(gestionnaire, middleware, transaction).await,
+        HandlerTransaction::SoumettreMessage => transaction_soumettre_message(gestionnaire, middleware, transaction).await,
     }
 }
 
@@ -38,17 +76,8 @@ where
 {
     debug!("transactions.consommer_transaction Consommer transaction : {:?}", &m.message);
 
-    // Autorisation
-    match m.action.as_str() {
-        // 4.secure - doivent etre validees par une commande
-        TRANSACTION_CREER_NOUVELLE_APPLICATION => {
-            match m.verifier_exchanges(vec![Securite::L4Secure]) {
-                true => Ok(()),
-                false => Err(format!("transactions.consommer_transaction: Message autorisation invalide (pas 4.secure)"))
-            }?;
-        },
-        _ => Err(format!("transactions.consommer_transaction: Mauvais type d'action pour une transaction : {}", m.action))?,
-    }
+    // Autorisation - les transactions doivent etre validees par une commande (4.secure)
+    autoriser_action(&m, TypeAction::Transaction)?;
 
     Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
@@ -92,6 +121,7 @@ async fn transaction_creer_nouvelle_application<M,T>(gestionnaire: &Gestionnaire
     if let Err(e) = collection.update_one(filtre, ops, options).await {
         Err(format!("Erreur insertion/update application_id {} : {:?}", uuid_transaction, e))?
     }
+    notifier_application_maj(middleware, &user_id, &uuid_transaction).await;
 
     let reponse = json!({ "ok": true, "application_id": &uuid_transaction });
 
@@ -124,16 +154,24 @@ async fn transaction_sauvegarder_application<M,T>(gestionnaire: &GestionnaireLan
 
     let filtre = doc! { CHAMP_APPLICATION_ID: &transaction_application.application_id, CHAMP_USER_ID: &user_id };
 
-    let actif = match transaction_application.actif.as_ref() {
-        Some(b) => b.to_owned(),
-        None => false
-    };
+    // Le nom, l'activation et les champs sont conserves dans le brouillon jusqu'a la publication
+    let mut set_ops = doc! {};
+    if let Some(nom) = transaction_application.nom.as_ref() {
+        set_ops.insert("brouillon.nom", nom);
+    }
+    if let Some(actif) = transaction_application.actif {
+        set_ops.insert("brouillon.actif", actif);
+    }
+    if let Some(champs) = transaction_application.champs.as_ref() {
+        // Champs valides par la commande, la transaction est appliquee telle quelle (regeneration)
+        let champs = trier_champs_formulaire(champs);
+        match convertir_to_bson_array(champs) {
+            Ok(inner) => set_ops.insert("brouillon.champs", inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_application Erreur conversion champs : {:?}", e))?
+        };
+    }
 
-    let ops = doc! {
-        "$set": {
-            "nom": transaction_application.nom.as_ref(),
-            "actif": actif,
-        },
+    let mut ops = doc! {
         "$setOnInsert": {
             CHAMP_APPLICATION_ID: &uuid_transaction,
             CHAMP_USER_ID: &user_id,
@@ -143,6 +181,10 @@ async fn transaction_sauvegarder_application<M,T>(gestionnaire: &GestionnaireLan
             CHAMP_MODIFICATION: true,
         }
     };
+    // Mongo refuse un $set vide
+    if ! set_ops.is_empty() {
+        ops.insert("$set", set_ops);
+    }
 
     let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
     let options = UpdateOptions::builder()
@@ -151,6 +193,7 @@ async fn transaction_sauvegarder_application<M,T>(gestionnaire: &GestionnaireLan
     if let Err(e) = collection.update_one(filtre, ops, options).await {
         Err(format!("Erreur insertion/update application_id {} : {:?}", uuid_transaction, e))?
     }
+    notifier_application_maj(middleware, &user_id, &transaction_application.application_id).await;
 
     let reponse = json!({ "ok": true, "application_id": &uuid_transaction });
 
@@ -160,6 +203,1254 @@ async fn transaction_sauvegarder_application<M,T>(gestionnaire: &GestionnaireLan
     }
 }
 
+async fn transaction_sauvegarder_page_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_page_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_page: TransactionSauvegarderPageApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_page_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    valider_page_application(&transaction_page.page)?;
+    let page_bson = match convertir_to_bson(&transaction_page.page) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_page_application Erreur conversion page : {:?}", e))?
+    };
+
+    let champ_page_brouillon = format!("{}.{}", CHAMP_BROUILLON, CHAMP_PAGE);
+    let set_ops = doc! { champ_page_brouillon: page_bson };
+    maj_application(middleware, &transaction_page.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_supprimer_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_supprimer_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+    let date_suppression = transaction.get_estampille().timestamp();
+
+    let transaction_application: TransactionSupprimerApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_supprimer_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    // La date de suppression vient de la transaction, la purge reste correcte apres une regeneration
+    let set_ops = doc! {
+        CHAMP_ACTIF: false,
+        CHAMP_SUPPRIME: true,
+        CHAMP_DATE_SUPPRESSION: date_suppression,
+    };
+    maj_application(middleware, &transaction_application.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_supprimer_applications_usager<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_supprimer_applications_usager Consommer transaction : {:?}", &transaction);
+    let date_suppression = transaction.get_estampille().timestamp();
+
+    let transaction_usager: TransactionSupprimerApplicationsUsager = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_supprimer_applications_usager Erreur conversion transaction : {:?}", e))?
+    };
+    let user_id = transaction_usager.user_id.as_str();
+
+    // Les applications vont a la corbeille comme avec supprimerApplication, la purge retire les
+    // versions et compteurs. Les messages, transferts et blocages sont retires immediatement.
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_SUPPRIME: {"$ne": true} };
+    let ops = doc! {
+        "$set": { CHAMP_ACTIF: false, CHAMP_SUPPRIME: true, CHAMP_DATE_SUPPRESSION: date_suppression },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection_applications = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    if let Err(e) = collection_applications.update_many(filtre, ops, None).await {
+        Err(format!("transactions.transaction_supprimer_applications_usager Erreur maj applications : {:?}", e))?
+    }
+
+    let application_ids: Vec<String> = match collection_applications.distinct(CHAMP_APPLICATION_ID, doc! { CHAMP_USER_ID: user_id }, None).await {
+        Ok(inner) => inner.into_iter().filter_map(|v| v.as_str().map(|a| a.to_owned())).collect(),
+        Err(e) => Err(format!("transactions.transaction_supprimer_applications_usager Erreur chargement applications : {:?}", e))?
+    };
+
+    let filtre_usager = doc! { CHAMP_USER_ID: user_id };
+    for nom_collection in [NOM_COLLECTION_MESSAGES, NOM_COLLECTION_TRANSFERTS] {
+        let collection = middleware.get_collection(nom_collection)?;
+        if let Err(e) = collection.delete_many(filtre_usager.clone(), None).await {
+            Err(format!("transactions.transaction_supprimer_applications_usager Erreur suppression {} : {:?}", nom_collection, e))?
+        }
+    }
+    if ! application_ids.is_empty() {
+        let filtre_ids = doc! { CHAMP_APPLICATION_ID: {"$in": &application_ids} };
+        let collection = middleware.get_collection(NOM_COLLECTION_BLOCAGES)?;
+        if let Err(e) = collection.delete_many(filtre_ids, None).await {
+            Err(format!("transactions.transaction_supprimer_applications_usager Erreur suppression blocages : {:?}", e))?
+        }
+    }
+
+    for application_id in application_ids.iter() {
+        notifier_application_maj(middleware, user_id, application_id).await;
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true, "applications": application_ids.len()}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_supprimer_applications_usager Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_archiver_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_archiver_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_application: TransactionArchiverApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_archiver_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let archive = transaction_application.archive.unwrap_or(true);
+    let mut set_ops = doc! { CHAMP_ARCHIVE: archive };
+    if archive {
+        // Une application archivee ne recoit plus de messages
+        set_ops.insert(CHAMP_ACTIF, false);
+    }
+    maj_application(middleware, &transaction_application.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_restaurer_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_restaurer_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_application: TransactionRestaurerApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_restaurer_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let set_ops = doc! {
+        CHAMP_SUPPRIME: false,
+        CHAMP_DATE_SUPPRESSION: None::<i64>,
+    };
+    maj_application(middleware, &transaction_application.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_publier_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_publier_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+    let date_publication = transaction.get_estampille().timestamp();
+
+    let transaction_publier: TransactionPublierApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_publier_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let application = charger_application_transaction(middleware, &transaction_publier.application_id, &user_id).await?;
+    let contenu = match application.brouillon.as_ref() {
+        Some(inner) => inner.clone(),
+        None => Err(format!("transactions.transaction_publier_application Aucun brouillon pour application {}", application.application_id))?
+    };
+
+    publier_contenu(middleware, &application, contenu, date_publication, None).await
+}
+
+async fn transaction_restaurer_version_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_restaurer_version_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+    let date_publication = transaction.get_estampille().timestamp();
+
+    let transaction_restaurer: TransactionRestaurerVersionApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_restaurer_version_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let application = charger_application_transaction(middleware, &transaction_restaurer.application_id, &user_id).await?;
+
+    let version: DocVersionApplication = {
+        let filtre = doc! {
+            CHAMP_APPLICATION_ID: &transaction_restaurer.application_id,
+            CHAMP_USER_ID: &user_id,
+            CHAMP_VERSION: transaction_restaurer.version,
+        };
+        let collection = middleware.get_collection(NOM_COLLECTION_VERSIONS)?;
+        match collection.find_one(filtre, None).await {
+            Ok(Some(d)) => match convertir_bson_deserializable(d) {
+                Ok(inner) => inner,
+                Err(e) => Err(format!("transactions.transaction_restaurer_version_application Erreur mapping version : {:?}", e))?
+            },
+            Ok(None) => Err(format!("transactions.transaction_restaurer_version_application Version {} inconnue", transaction_restaurer.version))?,
+            Err(e) => Err(format!("transactions.transaction_restaurer_version_application Erreur chargement version : {:?}", e))?
+        }
+    };
+
+    // Le retour arriere est publie comme nouvelle version, l'historique reste immuable.
+    // L'activation courante est conservee.
+    let mut contenu = version.contenu;
+    contenu.actif = None;
+    publier_contenu(middleware, &application, contenu, date_publication, Some(version.version)).await
+}
+
+async fn transaction_sauvegarder_horaire_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_horaire_application Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_horaire: TransactionSauvegarderHoraireApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_horaire_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let horaire = match transaction_horaire.horaire.as_ref() {
+        Some(h) => match convertir_to_bson(h) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_horaire_application Erreur conversion horaire : {:?}", e))?
+        },
+        None => None
+    };
+
+    // Remplace l'horaire au complet. L'etat applique est reinitialise, la cedule va
+    // appliquer le nouvel horaire a la prochaine execution.
+    let set_ops = doc! {
+        CHAMP_DATE_OUVERTURE: transaction_horaire.date_ouverture,
+        CHAMP_DATE_FERMETURE: transaction_horaire.date_fermeture,
+        CHAMP_HORAIRE: horaire,
+        CHAMP_OUVERT_HORAIRE: None::<bool>,
+    };
+    maj_application(middleware, &transaction_horaire.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_limite_soumissions<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_limite_soumissions Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_limite: TransactionSauvegarderLimiteSoumissions = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_limite_soumissions Erreur conversion transaction : {:?}", e))?
+    };
+
+    let limite = match transaction_limite.limite.as_ref() {
+        Some(l) => match convertir_to_bson(l) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_limite_soumissions Erreur conversion limite : {:?}", e))?
+        },
+        None => None
+    };
+
+    let set_ops = doc! { CHAMP_LIMITE_SOUMISSIONS: limite };
+    maj_application(middleware, &transaction_limite.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_limite_debit<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_limite_debit Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_limite: TransactionSauvegarderLimiteDebit = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_limite_debit Erreur conversion transaction : {:?}", e))?
+    };
+
+    let limite_application = match transaction_limite.application.as_ref() {
+        Some(l) => match convertir_to_bson(l) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_limite_debit Erreur conversion limite : {:?}", e))?
+        },
+        None => None
+    };
+    let limite_source = match transaction_limite.source.as_ref() {
+        Some(l) => match convertir_to_bson(l) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_limite_debit Erreur conversion limite : {:?}", e))?
+        },
+        None => None
+    };
+
+    let set_ops = doc! {
+        CHAMP_LIMITE_DEBIT: limite_application,
+        CHAMP_LIMITE_DEBIT_SOURCE: limite_source,
+    };
+    maj_application(middleware, &transaction_limite.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_difficulte_defi<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_difficulte_defi Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_defi: TransactionSauvegarderDifficulteDefi = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_difficulte_defi Erreur conversion transaction : {:?}", e))?
+    };
+
+    let set_ops = doc! { CHAMP_DIFFICULTE_DEFI: transaction_defi.difficulte };
+    maj_application(middleware, &transaction_defi.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_configuration_spam<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_configuration_spam Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_spam: TransactionSauvegarderConfigurationSpam = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_configuration_spam Erreur conversion transaction : {:?}", e))?
+    };
+
+    let configuration = match transaction_spam.configuration.as_ref() {
+        Some(c) => match convertir_to_bson(c) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_configuration_spam Erreur conversion configuration : {:?}", e))?
+        },
+        None => None
+    };
+
+    let set_ops = doc! { CHAMP_CONFIGURATION_SPAM: configuration };
+    maj_application(middleware, &transaction_spam.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_limites_fichiers<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_limites_fichiers Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_limites: TransactionSauvegarderLimitesFichiers = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_limites_fichiers Erreur conversion transaction : {:?}", e))?
+    };
+
+    let limites = match transaction_limites.limites.as_ref() {
+        Some(c) => match convertir_to_bson(c) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_sauvegarder_limites_fichiers Erreur conversion limites : {:?}", e))?
+        },
+        None => None
+    };
+
+    let set_ops = doc! { CHAMP_LIMITES_FICHIERS: limites };
+    maj_application(middleware, &transaction_limites.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_sauvegarder_transfert_messagerie<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_sauvegarder_transfert_messagerie Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_transfert: TransactionSauvegarderTransfertMessagerie = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_sauvegarder_transfert_messagerie Erreur conversion transaction : {:?}", e))?
+    };
+
+    let set_ops = doc! { CHAMP_TRANSFERT_MESSAGERIE: transaction_transfert.actif };
+    maj_application(middleware, &transaction_transfert.application_id, &user_id, set_ops).await
+}
+
+async fn transaction_liberer_messages<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_liberer_messages Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_liberer: TransactionLibererMessages = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &transaction_liberer.message_ids},
+        CHAMP_USER_ID: &user_id,
+        CHAMP_QUARANTAINE: true,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let mut messages = Vec::new();
+    match collection.find(filtre.clone(), None).await {
+        Ok(mut curseur) => while let Some(d) = curseur.next().await {
+            match d {
+                Ok(inner) => match convertir_bson_deserializable::<DocMessage>(inner) {
+                    Ok(m) => messages.push(m),
+                    Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur mapping message : {:?}", e))?
+                },
+                Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur lecture messages : {:?}", e))?
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur chargement messages : {:?}", e))?
+    }
+
+    // Les messages liberes sont comptes a leur date de reception. Un message qui depasse la limite
+    // de soumissions de sa periode reste en quarantaine.
+    let mut applications: HashMap<String, DocApplication> = HashMap::new();
+    let nombre_messages = messages.len();
+    let mut liberes = Vec::new();
+    for message in messages.into_iter() {
+        let date_reception = match Utc.timestamp_opt(message.date_reception, 0).single() {
+            Some(inner) => inner,
+            None => Err(format!("transactions.transaction_liberer_messages Date invalide message {}", message.message_id))?
+        };
+        if ! applications.contains_key(&message.application_id) {
+            let application = charger_application_transaction(middleware, &message.application_id, &user_id).await?;
+            applications.insert(message.application_id.clone(), application);
+        }
+        let application = applications.get(&message.application_id).expect("application");
+        if incrementer_compteurs(middleware, application, &date_reception).await? {
+            liberes.push(message);
+        } else {
+            debug!("transaction_liberer_messages Limite atteinte, message {} reste en quarantaine", message.message_id);
+        }
+    }
+    let refuses = nombre_messages - liberes.len();
+    let messages = liberes;
+
+    let message_ids: Vec<&String> = messages.iter().map(|m| &m.message_id).collect();
+    let filtre = doc! { CHAMP_MESSAGE_ID: {"$in": message_ids}, CHAMP_USER_ID: &user_id };
+    let ops = doc! {
+        "$set": { CHAMP_QUARANTAINE: false },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    if let Err(e) = collection.update_many(filtre, ops, None).await {
+        Err(format!("transactions.transaction_liberer_messages Erreur maj messages : {:?}", e))?
+    }
+
+    // Transferer les messages liberes des applications qui transferent vers Messagerie
+    if ! messages.is_empty() && middleware.get_mode_regeneration() == false {
+        let mut application_ids: Vec<&String> = messages.iter().map(|m| &m.application_id).collect();
+        application_ids.sort();
+        application_ids.dedup();
+        let filtre = doc! {
+            CHAMP_APPLICATION_ID: {"$in": application_ids},
+            CHAMP_USER_ID: &user_id,
+            CHAMP_TRANSFERT_MESSAGERIE: true,
+        };
+        let collection_applications = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+        let applications_transfert: Vec<String> = match collection_applications.distinct(CHAMP_APPLICATION_ID, filtre, None).await {
+            Ok(inner) => inner.into_iter().filter_map(|v| v.as_str().map(|a| a.to_owned())).collect(),
+            Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur chargement applications : {:?}", e))?
+        };
+        for message in messages.iter().filter(|m| applications_transfert.contains(&m.application_id)) {
+            ajouter_transfert(middleware, &message.message_id, &message.application_id, &user_id).await?;
+        }
+    }
+
+    // Les messages liberes apparaissent dans la boite de reception
+    if middleware.get_mode_regeneration() == false {
+        for message in messages.iter() {
+            if let Err(e) = emettre_evenement_nouveau_message(middleware, &user_id, &message.application_id, &message.message_id).await {
+                error!("transactions.transaction_liberer_messages Message {} : {}", message.message_id, e);
+            }
+        }
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true, "liberes": messages.len(), "complet": refuses}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_liberer_messages Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_supprimer_messages<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_supprimer_messages Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_supprimer: TransactionSupprimerMessages = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_supprimer_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &transaction_supprimer.message_ids},
+        CHAMP_USER_ID: &user_id,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let supprimes = match collection.delete_many(filtre, None).await {
+        Ok(r) => r.deleted_count,
+        Err(e) => Err(format!("transactions.transaction_supprimer_messages Erreur suppression messages : {:?}", e))?
+    };
+
+    match middleware.formatter_reponse(json!({"ok": true, "supprimes": supprimes}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_supprimer_messages Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_changer_etat_messages<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_changer_etat_messages Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+    let date_etat = transaction.get_estampille().timestamp();
+
+    let transaction_etat: TransactionChangerEtatMessages = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_changer_etat_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    // Appliquer la transition seulement aux messages dans un etat precedent permis
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &transaction_etat.message_ids},
+        CHAMP_USER_ID: &user_id,
+        CHAMP_ETAT: filtre_etats_messages(transaction_etat.etat.etats_precedents()),
+    };
+    let ops = doc! {
+        "$set": { CHAMP_ETAT: transaction_etat.etat.as_str(), CHAMP_DATE_ETAT: date_etat },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let modifies = match collection.update_many(filtre, ops, None).await {
+        Ok(r) => r.modified_count,
+        Err(e) => Err(format!("transactions.transaction_changer_etat_messages Erreur maj messages : {:?}", e))?
+    };
+
+    match middleware.formatter_reponse(json!({"ok": true, "modifies": modifies}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_changer_etat_messages Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_etiqueter_messages<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_etiqueter_messages Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_etiquettes: TransactionEtiqueterMessages = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_etiqueter_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &transaction_etiquettes.message_ids},
+        CHAMP_USER_ID: &user_id,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+
+    // $addToSet et $pull ne peuvent pas etre combines sur le meme champ
+    if let Some(ajouter) = transaction_etiquettes.ajouter.as_ref() {
+        let ajouter = normaliser_etiquettes(ajouter)?;
+        let ops = doc! {
+            "$addToSet": { CHAMP_ETIQUETTES: {"$each": ajouter} },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        if let Err(e) = collection.update_many(filtre.clone(), ops, None).await {
+            Err(format!("transactions.transaction_etiqueter_messages Erreur ajout etiquettes : {:?}", e))?
+        }
+    }
+    if let Some(retirer) = transaction_etiquettes.retirer.as_ref() {
+        let retirer = normaliser_etiquettes(retirer)?;
+        let ops = doc! {
+            "$pull": { CHAMP_ETIQUETTES: {"$in": retirer} },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        if let Err(e) = collection.update_many(filtre, ops, None).await {
+            Err(format!("transactions.transaction_etiqueter_messages Erreur retrait etiquettes : {:?}", e))?
+        }
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_etiqueter_messages Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_assigner_messages<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_assigner_messages Consommer transaction : {:?}", &transaction);
+    let user_id = get_user_id_transaction(&transaction)?;
+
+    let transaction_assigner: TransactionAssignerMessages = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_assigner_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! {
+        CHAMP_MESSAGE_ID: {"$in": &transaction_assigner.message_ids},
+        CHAMP_USER_ID: &user_id,
+    };
+    let assigne = transaction_assigner.assigne.as_ref().map(|a| a.trim());
+    let ops = doc! {
+        "$set": { CHAMP_ASSIGNE: assigne },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+    let modifies = match collection.update_many(filtre, ops, None).await {
+        Ok(r) => r.modified_count,
+        Err(e) => Err(format!("transactions.transaction_assigner_messages Erreur maj messages : {:?}", e))?
+    };
+
+    match middleware.formatter_reponse(json!({"ok": true, "modifies": modifies}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_assigner_messages Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_bloquer_source<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_bloquer_source Consommer transaction : {:?}", &transaction);
+    let date_blocage = transaction.get_estampille().timestamp();
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => e.get_user_id()?.map(|u| u.to_owned()),
+        None => None
+    };
+
+    let transaction_bloquer: TransactionBloquerSource = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_bloquer_source Erreur conversion transaction : {:?}", e))?
+    };
+
+    // Blocage d'une application : conserver le proprietaire. Blocage global : aucun usager.
+    let user_id_blocage = match transaction_bloquer.application_id.as_ref() {
+        Some(application_id) => {
+            let user_id = match user_id {
+                Some(inner) => inner,
+                None => Err(format!("transactions.transaction_bloquer_source User_id absent du certificat"))?
+            };
+            let application = charger_application_transaction(middleware, application_id, &user_id).await?;
+            Some(application.user_id)
+        },
+        None => None
+    };
+
+    let filtre = doc! {
+        CHAMP_APPLICATION_ID: &transaction_bloquer.application_id,
+        CHAMP_SOURCE: &transaction_bloquer.source,
+    };
+    let ops = doc! {
+        "$set": { "raison": &transaction_bloquer.raison },
+        "$setOnInsert": {
+            CHAMP_APPLICATION_ID: &transaction_bloquer.application_id,
+            CHAMP_USER_ID: user_id_blocage,
+            CHAMP_SOURCE: &transaction_bloquer.source,
+            "date_blocage": date_blocage,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_BLOCAGES)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(e) = collection.update_one(filtre, ops, options).await {
+        Err(format!("transactions.transaction_bloquer_source Erreur sauvegarde blocage : {:?}", e))?
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_bloquer_source Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn transaction_debloquer_source<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_debloquer_source Consommer transaction : {:?}", &transaction);
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => e.get_user_id()?.map(|u| u.to_owned()),
+        None => None
+    };
+
+    let transaction_debloquer: TransactionDebloquerSource = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_debloquer_source Erreur conversion transaction : {:?}", e))?
+    };
+
+    let mut filtre = doc! {
+        CHAMP_APPLICATION_ID: &transaction_debloquer.application_id,
+        CHAMP_SOURCE: &transaction_debloquer.source,
+    };
+    if transaction_debloquer.application_id.is_some() {
+        match user_id {
+            Some(inner) => filtre.insert(CHAMP_USER_ID, inner),
+            None => Err(format!("transactions.transaction_debloquer_source User_id absent du certificat"))?
+        };
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_BLOCAGES)?;
+    if let Err(e) = collection.delete_one(filtre, None).await {
+        Err(format!("transactions.transaction_debloquer_source Erreur suppression blocage : {:?}", e))?
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_debloquer_source Erreur preparation reponse : {:?}", e))
+    }
+}
+
+/// Transaction emise par le domaine : aucun user_id dans le certificat, l'application est
+/// chargee pour retrouver son proprietaire.
+async fn transaction_appliquer_horaire_application<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_appliquer_horaire_application Consommer transaction : {:?}", &transaction);
+
+    let transaction_appliquer: TransactionAppliquerHoraireApplication = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_appliquer_horaire_application Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! { CHAMP_APPLICATION_ID: &transaction_appliquer.application_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application: DocApplication = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("transactions.transaction_appliquer_horaire_application Erreur mapping application : {:?}", e))?
+        },
+        Ok(None) => Err(format!("transactions.transaction_appliquer_horaire_application Application {} inconnue", transaction_appliquer.application_id))?,
+        Err(e) => Err(format!("transactions.transaction_appliquer_horaire_application Erreur chargement application : {:?}", e))?
+    };
+
+    // ouvert_horaire conserve la derniere transition. Un changement manuel de actif par le
+    // proprietaire est conserve jusqu'a la prochaine transition de l'horaire.
+    let set_ops = doc! {
+        CHAMP_ACTIF: transaction_appliquer.actif,
+        CHAMP_OUVERT_HORAIRE: transaction_appliquer.actif,
+    };
+    maj_application(middleware, &application.application_id, &application.user_id, set_ops).await
+}
+
+async fn transaction_purger_applications<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_purger_applications Consommer transaction : {:?}", &transaction);
+
+    let transaction_purger: TransactionPurgerApplications = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_purger_applications Erreur conversion transaction : {:?}", e))?
+    };
+
+    // Seules les applications encore dans la corbeille sont purgees (restauration entre
+    // l'emission et le traitement de la transaction).
+    let filtre = doc! { CHAMP_APPLICATION_ID: {"$in": &transaction_purger.application_ids}, CHAMP_SUPPRIME: true };
+    let collection_applications = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    let application_ids: Vec<String> = match collection_applications.distinct(CHAMP_APPLICATION_ID, filtre, None).await {
+        Ok(inner) => inner.into_iter().filter_map(|v| v.as_str().map(|a| a.to_owned())).collect(),
+        Err(e) => Err(format!("transactions.transaction_purger_applications Erreur chargement applications : {:?}", e))?
+    };
+
+    if ! application_ids.is_empty() {
+        info!("transaction_purger_applications Purger {} applications : {:?}", application_ids.len(), application_ids);
+        // Fichiers joints aux messages et images des pages, charges avant la purge des documents
+        let fuuids = charger_fuuids_applications(middleware, &application_ids).await?;
+
+        let filtre_ids = doc! { CHAMP_APPLICATION_ID: {"$in": &application_ids} };
+        let collections = [
+            NOM_COLLECTION_MESSAGES,
+            NOM_COLLECTION_VERSIONS,
+            NOM_COLLECTION_COMPTEURS,
+            NOM_COLLECTION_STATISTIQUES,
+            NOM_COLLECTION_BLOCAGES,
+            NOM_COLLECTION_TRANSFERTS,
+            NOM_COLLECTION_JETONS,
+            NOM_COLLECTION_DEFIS,
+            NOM_COLLECTION_APPLICATIONS,
+        ];
+        for nom_collection in collections {
+            let collection = middleware.get_collection(nom_collection)?;
+            if let Err(e) = collection.delete_many(filtre_ids.clone(), None).await {
+                Err(format!("transactions.transaction_purger_applications Erreur purge {} : {:?}", nom_collection, e))?
+            }
+        }
+
+        // Liberer les fichiers qui ne sont plus references. En regeneration, aucun evenement n'est
+        // emis : la collecte des fichiers orphelins les liberera.
+        if middleware.get_mode_regeneration() == false {
+            let references = trouver_fuuids_references(middleware, &fuuids).await?;
+            let orphelins: Vec<String> = fuuids.into_iter().filter(|f| ! references.contains(f)).collect();
+            if ! orphelins.is_empty() {
+                debug!("transaction_purger_applications Liberer {} fichiers", orphelins.len());
+                liberer_fuuids(middleware, orphelins).await?;
+            }
+        }
+    }
+
+    match middleware.formatter_reponse(json!({"ok": true, "purgees": application_ids.len()}), None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_purger_applications Erreur preparation reponse : {:?}", e))
+    }
+}
+
+async fn charger_application_transaction<M>(middleware: &M, application_id: &str, user_id: &str)
+    -> Result<DocApplication, String>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_APPLICATION_ID: application_id, CHAMP_USER_ID: user_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => Ok(inner),
+            Err(e) => Err(format!("transactions.charger_application_transaction Erreur mapping application : {:?}", e))
+        },
+        Ok(None) => Err(format!("transactions.charger_application_transaction Application {} inconnue pour usager {}", application_id, user_id)),
+        Err(e) => Err(format!("transactions.charger_application_transaction Erreur chargement application : {:?}", e))
+    }
+}
+
+/// Conserve une nouvelle version (immuable) et la rend publique. Le brouillon prend le contenu publie.
+async fn publier_contenu<M>(middleware: &M, application: &DocApplication, contenu: ContenuApplication,
+                            date_publication: i64, version_source: Option<i64>)
+    -> Result<Option<MessageMilleGrille>, String>
+    where M: GenerateurMessages + MongoDao
+{
+    let version = application.version_publiee.unwrap_or(0) + 1;
+
+    let doc_version = DocVersionApplication {
+        application_id: application.application_id.clone(),
+        user_id: application.user_id.clone(),
+        version,
+        contenu: contenu.clone(),
+        date_publication,
+        version_source,
+    };
+    let bson_version = match convertir_to_bson(&doc_version) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.publier_contenu Erreur conversion version : {:?}", e))?
+    };
+    let filtre_version = doc! { CHAMP_APPLICATION_ID: &application.application_id, CHAMP_VERSION: version };
+    let ops_version = doc! { "$setOnInsert": bson_version };
+    let collection_versions = middleware.get_collection(NOM_COLLECTION_VERSIONS)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(e) = collection_versions.update_one(filtre_version, ops_version, options).await {
+        Err(format!("transactions.publier_contenu Erreur insertion version {} : {:?}", version, e))?
+    }
+
+    let bson_contenu = match convertir_to_bson(&contenu) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.publier_contenu Erreur conversion contenu : {:?}", e))?
+    };
+    let mut set_ops = bson_contenu.clone();
+    if contenu.actif.is_none() {
+        set_ops.remove(CHAMP_ACTIF);
+    }
+    set_ops.insert(CHAMP_VERSION_PUBLIEE, version);
+    set_ops.insert(CHAMP_BROUILLON, bson_contenu);
+
+    let reponse = maj_application(middleware, &application.application_id, &application.user_id, set_ops).await?;
+    debug!("transactions.publier_contenu Application {} publiee version {}", application.application_id, version);
+    Ok(reponse)
+}
+
+/// Retourne les fuuids deja consignes (evenement recu avant la transaction).
+async fn charger_fuuids_consignes<M>(middleware: &M, fuuids: &Vec<String>) -> Result<Vec<String>, String>
+    where M: MongoDao
+{
+    let mut fuuids_consignes = Vec::new();
+    if fuuids.is_empty() {
+        return Ok(fuuids_consignes)
+    }
+
+    let filtre = doc! { CHAMP_FUUID: {"$in": fuuids}, CHAMP_ETAT: "consigne" };
+    let collection = middleware.get_collection(NOM_COLLECTION_FICHIERS)?;
+    let mut curseur = match collection.find(filtre, None).await {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.charger_fuuids_consignes Erreur chargement fichiers : {:?}", e))?
+    };
+    while let Some(d) = curseur.next().await {
+        let fichier: DocFichier = match d {
+            Ok(inner) => match convertir_bson_deserializable(inner) {
+                Ok(f) => f,
+                Err(e) => Err(format!("transactions.charger_fuuids_consignes Erreur mapping fichier : {:?}", e))?
+            },
+            Err(e) => Err(format!("transactions.charger_fuuids_consignes Erreur lecture fichiers : {:?}", e))?
+        };
+        fuuids_consignes.push(fichier.fuuid);
+    }
+
+    Ok(fuuids_consignes)
+}
+
+/// Reserve une place pour une soumission et incremente les compteurs (total et chaque periode)
+/// d'une application. Retourne false, sans rien compter, lorsque la limite de soumissions de
+/// l'application est atteinte pour la periode de la date.
+async fn incrementer_compteurs<M>(middleware: &M, application: &DocApplication, date: &DateTime<Utc>) -> Result<bool, String>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_COMPTEURS)?;
+
+    // Reservation atomique sur le compteur de la limite. Lorsque le compteur est deja au maximum,
+    // le filtre ne correspond pas et l'upsert echoue sur l'index unique (application_id, periode).
+    let cle_limite = match application.limite_soumissions.as_ref() {
+        Some(limite) => {
+            let cle = limite.cle_periode(date);
+            let filtre = doc! {
+                CHAMP_APPLICATION_ID: &application.application_id,
+                CHAMP_PERIODE: &cle,
+                CHAMP_NOMBRE: {"$lt": limite.maximum},
+            };
+            let ops = doc! { "$inc": { CHAMP_NOMBRE: 1 } };
+            let options = UpdateOptions::builder().upsert(true).build();
+            match collection.update_one(filtre, ops, options).await {
+                Ok(_) => (),
+                Err(e) => match *e.kind {
+                    ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == 11000 => return Ok(false),
+                    _ => Err(format!("transactions.incrementer_compteurs Erreur reservation {} : {:?}", application.application_id, e))?
+                }
+            }
+            Some(cle)
+        },
+        None => None
+    };
+
+    let periodes = [None, Some(PeriodeLimite::Jour), Some(PeriodeLimite::Semaine), Some(PeriodeLimite::Mois)];
+    for periode in periodes.iter() {
+        let cle = cle_periode(periode.as_ref(), date);
+        if cle_limite.as_ref() == Some(&cle) { continue }  // Deja incremente par la reservation
+        let filtre = doc! {
+            CHAMP_APPLICATION_ID: &application.application_id,
+            CHAMP_PERIODE: cle,
+        };
+        let ops = doc! { "$inc": { CHAMP_NOMBRE: 1 } };
+        let options = UpdateOptions::builder().upsert(true).build();
+        if let Err(e) = collection.update_one(filtre, ops, options).await {
+            Err(format!("transactions.incrementer_compteurs Erreur maj compteur {} : {:?}", application.application_id, e))?
+        }
+    }
+    Ok(true)
+}
+
+fn get_user_id_transaction<T>(transaction: &T) -> Result<String, String>
+    where T: Transaction
+{
+    match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()? {
+            Some(inner) => Ok(inner.to_owned()),
+            None => Err(format!("transactions.get_user_id_transaction User_id absent du certificat (cert)"))
+        },
+        None => Err(format!("transactions.get_user_id_transaction User_id absent du certificat (enveloppe)"))
+    }
+}
+
+/// Annonce la modification d'une application aux clients du proprietaire (autres appareils).
+/// Une erreur d'emission n'empeche pas le traitement de la transaction.
+async fn notifier_application_maj<M>(middleware: &M, user_id: &str, application_id: &str)
+    where M: GenerateurMessages
+{
+    if middleware.get_mode_regeneration() == true { return }
+    if let Err(e) = emettre_evenement_application_maj(middleware, user_id, application_id).await {
+        error!("transactions.notifier_application_maj Application {} : {}", application_id, e);
+    }
+}
+
+/// Applique un $set sur une application existante de l'usager.
+async fn maj_application<M>(middleware: &M, application_id: &str, user_id: &str, set_ops: Document)
+    -> Result<Option<MessageMilleGrille>, String>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_APPLICATION_ID: application_id, CHAMP_USER_ID: user_id };
+    let ops = doc! {
+        "$set": set_ops,
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+
+    let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => if r.matched_count != 1 {
+            Err(format!("transactions.maj_application Application {} inconnue pour usager {}", application_id, user_id))?
+        },
+        Err(e) => Err(format!("transactions.maj_application Erreur maj application_id {} : {:?}", application_id, e))?
+    }
+    notifier_application_maj(middleware, user_id, application_id).await;
+
+    let reponse = json!({ "ok": true, "application_id": application_id });
+    match middleware.formatter_reponse(reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.maj_application Erreur preparation reponse {} : {:?}", application_id, e))
+    }
+}
+
+async fn transaction_soumettre_message<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao,
+        T: Transaction
+{
+    debug!("transaction_soumettre_message Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+    let estampille = transaction.get_estampille().to_owned();
+    let date_reception = estampille.timestamp();
+    let certificat = transaction.get_enveloppe_certificat().map(|c| (
+        c.fingerprint.clone(),
+        c.verifier_exchanges(vec![Securite::L2Prive, Securite::L3Protege, Securite::L4Secure])
+    ));
+
+    let transaction_message: TransactionSoumettreMessage = match transaction.convertir() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur conversion transaction : {:?}", e))?
+    };
+    let source = certificat.map(|(fingerprint, relais)| {
+        let hachage = calculer_hachage_source(fingerprint.as_str(), relais, transaction_message.adresse_source.as_ref());
+        SourceSoumission { fingerprint, hachage }
+    });
+
+    // Le message appartient au proprietaire de l'application
+    let application: DocApplication = {
+        let filtre = doc! { CHAMP_APPLICATION_ID: &transaction_message.application_id };
+        let collection = middleware.get_collection(NOM_COLLECTION_APPLICATIONS)?;
+        match collection.find_one(filtre, None).await {
+            Ok(Some(d)) => match convertir_bson_deserializable(d) {
+                Ok(inner) => inner,
+                Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur mapping application : {:?}", e))?
+            },
+            Ok(None) => Err(format!("transactions.transaction_soumettre_message Application {} inconnue", transaction_message.application_id))?,
+            Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur chargement application : {:?}", e))?
+        }
+    };
+
+    // Detection de spam. Les valeurs utilisees proviennent de la transaction et de l'etat
+    // regenere (messages precedents), la regeneration donne le meme resultat.
+    let hachage_contenu = hacher_contenu(&transaction_message);
+    let resultat_spam = match application.configuration_spam.as_ref() {
+        Some(config) => {
+            let doublon = match config.detecter_doublons {
+                Some(true) => {
+                    let filtre = doc! {
+                        CHAMP_APPLICATION_ID: &transaction_message.application_id,
+                        CHAMP_HACHAGE_CONTENU: &hachage_contenu,
+                        CHAMP_MESSAGE_ID: {"$ne": &uuid_transaction},
+                        CHAMP_DATE_RECEPTION: {"$gte": date_reception - DUREE_DOUBLON_SPAM_SECONDES, "$lte": date_reception},
+                    };
+                    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+                    match collection.count_documents(filtre, None).await {
+                        Ok(n) => n > 0,
+                        Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur verification doublon : {:?}", e))?
+                    }
+                },
+                _ => false
+            };
+            let contexte = ContexteSpam {
+                transaction: &transaction_message,
+                delai_secondes: transaction_message.jeton_formulaire.as_ref().map(|j| date_reception - j.date_creation),
+                doublon,
+            };
+            Some(evaluer_spam(config, &contexte))
+        },
+        None => None
+    };
+    let quarantaine = resultat_spam.as_ref().map(|r| r.quarantaine).unwrap_or(false);
+    let bson_spam = match resultat_spam.as_ref() {
+        Some(r) => match convertir_to_bson(r) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur conversion spam : {:?}", e))?
+        },
+        None => None
+    };
+    if quarantaine {
+        debug!("transaction_soumettre_message Message {} en quarantaine : {:?}", uuid_transaction, resultat_spam);
+    }
+
+    // Fichiers joints. Un fichier peut avoir ete consigne avant la reception de la transaction.
+    let fuuids: Vec<String> = match transaction_message.fichiers.as_ref() {
+        Some(f) => f.iter().map(|f| f.fuuid.clone()).collect(),
+        None => Vec::new()
+    };
+    let fuuids_consignes = charger_fuuids_consignes(middleware, &fuuids).await?;
+    let fichiers_manquants: Vec<&String> = fuuids.iter().filter(|f| ! fuuids_consignes.contains(f)).collect();
+    let bson_fichiers = match transaction_message.fichiers.as_ref() {
+        Some(f) => match convertir_to_bson_array(f.clone()) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur conversion fichiers : {:?}", e))?
+        },
+        None => None
+    };
+
+    let filtre = doc! { CHAMP_MESSAGE_ID: &uuid_transaction };
+    let collection = middleware.get_collection(NOM_COLLECTION_MESSAGES)?;
+
+    // Reserver une place avant l'insertion. Le compteur est le seul point de decision : la
+    // verification de la commande n'est qu'un refus rapide. Une transaction recue a nouveau pour
+    // un message existant n'est pas comptee. Les compteurs sont vides avec les autres collections
+    // lors de la regeneration, le rejeu des transactions donne le meme resultat.
+    // Un message en quarantaine est compte lorsqu'il est libere.
+    let existant = match collection.count_documents(filtre.clone(), None).await {
+        Ok(n) => n > 0,
+        Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur verification message : {:?}", e))?
+    };
+    if ! existant && ! quarantaine {
+        if ! incrementer_compteurs(middleware, &application, &estampille).await? {
+            debug!("transaction_soumettre_message Limite atteinte, message {} refuse", uuid_transaction);
+            let reponse = json!({"ok": false, "err": "Limite de soumissions atteinte", "code": "complet"});
+            return match middleware.formatter_reponse(reponse, None) {
+                Ok(r) => Ok(Some(r)),
+                Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur preparation reponse {} : {:?}", uuid_transaction, e))
+            }
+        }
+    }
+
+    let bson_format: Bson = transaction_message.format.into();
+    let bson_enveloppe = match transaction_message.enveloppe.as_ref() {
+        Some(e) => match convertir_to_bson(e) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur conversion enveloppe : {:?}", e))?
+        },
+        None => None
+    };
+    let bson_cle_messagerie = match transaction_message.commande_maitredescles_messagerie.as_ref() {
+        Some(c) => match convertir_to_bson(c) {
+            Ok(inner) => Some(inner),
+            Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur conversion cle messagerie : {:?}", e))?
+        },
+        None => None
+    };
+    let ops = doc! {
+        "$setOnInsert": {
+            CHAMP_MESSAGE_ID: &uuid_transaction,
+            CHAMP_APPLICATION_ID: &transaction_message.application_id,
+            CHAMP_USER_ID: &application.user_id,
+            "data_chiffre": &transaction_message.data_chiffre,
+            "format": bson_format,
+            "header": &transaction_message.header,
+            "ref_hachage_bytes": &transaction_message.ref_hachage_bytes,
+            "enveloppe": bson_enveloppe,
+            CHAMP_DATE_RECEPTION: date_reception,
+            CHAMP_VERSION_APPLICATION: application.version_publiee,
+            CHAMP_LU: false,
+            CHAMP_HACHAGE_CONTENU: &hachage_contenu,
+            CHAMP_SOURCE: source.as_ref().map(|s| s.fingerprint.as_str()),
+            CHAMP_HACHAGE_SOURCE: source.as_ref().map(|s| s.hachage.as_str()),
+            "jeton_client": &transaction_message.jeton_client,
+            CHAMP_QUARANTAINE: quarantaine,
+            "spam": bson_spam,
+            "fichiers": bson_fichiers,
+            CHAMP_FUUIDS: &fuuids,
+            CHAMP_FUUIDS_CONSIGNES: &fuuids_consignes,
+            CHAMP_FICHIERS_MANQUANTS: &fichiers_manquants,
+            CHAMP_CLE_MESSAGERIE: bson_cle_messagerie,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": {
+            CHAMP_MODIFICATION: true,
+        }
+    };
+
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+    let resultat = match collection.update_one(filtre, ops, options).await {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("Erreur insertion message_id {} : {:?}", uuid_transaction, e))?
+    };
+
+    // Transfert vers Messagerie. Aucun transfert lors d'une regeneration, les messages ont deja
+    // ete transferes (ou le seront a partir de la collection transferts).
+    if resultat.upserted_id.is_some() && ! quarantaine && application.transfert_messagerie == Some(true)
+        && middleware.get_mode_regeneration() == false
+    {
+        ajouter_transfert(middleware, &uuid_transaction, &application.application_id, &application.user_id).await?;
+    }
+
+    // Mise a jour en direct des clients du proprietaire
+    if resultat.upserted_id.is_some() && ! quarantaine && middleware.get_mode_regeneration() == false {
+        if let Err(e) = emettre_evenement_nouveau_message(middleware, &application.user_id, &application.application_id, &uuid_transaction).await {
+            error!("transactions.transaction_soumettre_message Message {} : {}", uuid_transaction, e);
+        }
+    }
+
+    // Suivre l'etat des fichiers joints (attente de consignation) et les marquer comme references
+    // pour la collecte des fichiers orphelins.
+    if ! fuuids.is_empty() {
+        let collection_fichiers = middleware.get_collection(NOM_COLLECTION_FICHIERS)?;
+        for fuuid in fuuids.iter() {
+            let filtre = doc! { CHAMP_FUUID: fuuid };
+            let ops = doc! {
+                "$set": { CHAMP_REFERENCE: true },
+                "$setOnInsert": { CHAMP_FUUID: fuuid, CHAMP_ETAT: "attente", CHAMP_CREATION: Utc::now() },
+                "$currentDate": { CHAMP_MODIFICATION: true },
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            if let Err(e) = collection_fichiers.update_one(filtre, ops, options).await {
+                Err(format!("transactions.transaction_soumettre_message Erreur maj fichier {} : {:?}", fuuid, e))?
+            }
+        }
+    }
+
+    let reponse = json!({ "ok": true, "message_id": &uuid_transaction });
+
+    match middleware.formatter_reponse(reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_soumettre_message Erreur preparation confirmat envoi message {} : {:?}", uuid_transaction, e))
+    }
+}
+
 // async fn transaction_sauvegarder_groupe_usager<M,T>(gestionnaire: &GestionnaireLanding, middleware: &M, transaction: T)
 //     -> Result<Option<MessageMilleGrille>, String>
 //     where